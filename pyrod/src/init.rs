@@ -28,3 +28,15 @@ pub fn init() {
         .expect("Could not mount /proc");
     tracing::info!("Mounted /proc");
 }
+
+///Once we're done, reboot to make firecracker exit cleanly
+///The VM is booted with reboot=k, so this resets the keyboard controller, which firecracker
+///handles by shutting down (and flushing its metrics on the way out).
+///Just exiting would panic the kernel, which gets the same result but a second later.
+#[tracing::instrument]
+pub fn shutdown() {
+    //SAFETY - nothing else is running that cares, the whole VM is going away
+    unsafe {
+        libc::reboot(libc::RB_AUTOBOOT);
+    }
+}
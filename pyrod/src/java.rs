@@ -0,0 +1,313 @@
+//! `pyrod java`, which the java language compiles and runs through
+//! `build` finds the class with `main` and lays the sources out by package before running javac,
+//! and `run` starts the JVM with that class. Like `pyrod sql`, it runs as the untrusted user.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Where the sources are laid out, relative to the working directory
+const SRC: &str = "build/src";
+/// Where `build` leaves the name of the main class for `run`
+const MAIN_CLASS: &str = "build/main-class";
+
+/// `pyrod java build <source> <javac...>` or `pyrod java run [--flags=<flags>] <java...>`
+pub fn main(mut args: impl Iterator<Item = String>) -> std::process::ExitCode {
+    let outcome = match args.next().as_deref() {
+        Some("build") => build(args),
+        Some("run") => run(args),
+        _ => Err(anyhow!(
+            "Usage: pyrod java build <source> <javac...> | pyrod java run [--flags=<flags>] <java...>"
+        )),
+    };
+    //if it worked we exec'd and never got here
+    match outcome {
+        Ok(never) => match never {},
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
+
+/// What matters about a compilation unit
+#[derive(Debug, Default, PartialEq)]
+struct Unit {
+    package: Option<String>,
+    /// The public top-level type, which the file has to be named after
+    public: Option<String>,
+    /// Top-level types with a `public static void main`
+    mains: Vec<String>,
+}
+
+impl Unit {
+    fn qualify(&self, name: &str) -> String {
+        match &self.package {
+            Some(package) => format!("{package}.{name}"),
+            None => name.to_owned(),
+        }
+    }
+}
+
+/// Copy every `.java` file to a directory for its package, then compile them all
+/// The main class is the one in `source`, or if that hasn't got one, the only one anywhere
+fn build(mut args: impl Iterator<Item = String>) -> Result<std::convert::Infallible> {
+    let source = PathBuf::from(args.next().context("build needs the source file")?);
+    let javac: Vec<String> = args.collect();
+    let (program, javac_args) = javac.split_first().context("build needs a javac command")?;
+
+    let workdir = std::env::current_dir()?;
+    let source = workdir.join(source);
+    let mut files = vec![];
+    find_sources(&workdir, &mut files)?;
+    files.sort();
+
+    let mut laid_out: Vec<(PathBuf, PathBuf)> = vec![];
+    let (mut ours, mut theirs) = (vec![], vec![]);
+    for file in files {
+        let code =
+            std::fs::read_to_string(&file).with_context(|| format!("Could not read {file:?}"))?;
+        let unit = scan(&code);
+
+        let name = match &unit.public {
+            Some(public) => format!("{public}.java"),
+            None => file
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        let mut dest = PathBuf::from(SRC);
+        dest.extend(unit.package.iter().flat_map(|p| p.split('.')));
+        dest.push(name);
+
+        if let Some((other, _)) = laid_out.iter().find(|(_, d)| *d == dest) {
+            bail!("{other:?} and {file:?} would both be {dest:?}");
+        }
+        std::fs::create_dir_all(dest.parent().unwrap_or(Path::new(SRC)))?;
+        std::fs::write(&dest, &code).with_context(|| format!("Could not write {dest:?}"))?;
+
+        let mains = unit.mains.iter().map(|main| unit.qualify(main));
+        if file == source {
+            ours.extend(mains);
+        } else {
+            theirs.extend(mains);
+        }
+        laid_out.push((file, dest));
+    }
+
+    let candidates = if ours.is_empty() { theirs } else { ours };
+    let main = match candidates.as_slice() {
+        [main] => main,
+        [] => bail!("No class has a `public static void main(String[] args)` method"),
+        many => bail!(
+            "More than one class has a main method, there's: {}",
+            many.join(", ")
+        ),
+    };
+    std::fs::write(MAIN_CLASS, main)?;
+
+    Err(Command::new(program)
+        .args(javac_args)
+        .args(laid_out.iter().map(|(_, dest)| dest))
+        .exec())
+    .context("Could not run javac")
+}
+
+/// Start the JVM with the main class that `build` found, after any flags
+fn run(args: impl Iterator<Item = String>) -> Result<std::convert::Infallible> {
+    let mut java: Vec<String> = args.collect();
+    //one argument, so it can be an option that's a list
+    let flags: Vec<String> = match java.first().and_then(|arg| arg.strip_prefix("--flags=")) {
+        Some(flags) => {
+            let flags = flags.split_whitespace().map(String::from).collect();
+            java.remove(0);
+            flags
+        }
+        None => vec![],
+    };
+    let (program, java_args) = java.split_first().context("run needs a java command")?;
+
+    let main = std::fs::read_to_string(MAIN_CLASS).context("Could not read the main class")?;
+    Err(Command::new(program)
+        .args(java_args)
+        .args(flags)
+        .arg(main)
+        .exec())
+    .context("Could not run java")
+}
+
+/// Every `.java` file under `dir`
+fn find_sources(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Could not read {dir:?}"))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            //someone else's, like another run's hsperfdata
+            let _ = find_sources(&path, files);
+        } else if path.extension().is_some_and(|ext| ext == "java") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Java source, cut down to identifiers and the punctuation that matters for finding declarations
+/// Comments, string and char literals are skipped
+fn tokens(code: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut rest = code;
+    while let Some(c) = rest.chars().next() {
+        let skip = if rest.starts_with("//") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            comment.find("*/").map_or(rest.len(), |end| end + 4)
+        } else if let Some(block) = rest.strip_prefix("\"\"\"") {
+            block.find("\"\"\"").map_or(rest.len(), |end| end + 6)
+        } else if c == '"' || c == '\'' {
+            literal(rest, c)
+        } else if c.is_alphanumeric() || c == '_' || c == '$' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or(rest.len());
+            tokens.push(&rest[..len]);
+            len
+        } else {
+            if "{};().".contains(c) {
+                tokens.push(&rest[..1]);
+            }
+            c.len_utf8()
+        };
+        rest = &rest[skip..];
+    }
+    tokens
+}
+
+/// Length of a string or char literal starting at the start of `rest`, including the quotes
+fn literal(rest: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in rest.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '\n' => return i,
+            c if c == quote => return i + 1,
+            _ => {}
+        }
+    }
+    rest.len()
+}
+
+/// Find the package, public type and main methods in a compilation unit
+/// It's not a parser, but declarations only come in so many shapes
+fn scan(code: &str) -> Unit {
+    let mut unit = Unit::default();
+    let mut depth = 0;
+    //the top-level type we're in, and whether its methods are public without saying so
+    let mut current: Option<(String, bool)> = None;
+    //words since the last ; { or }
+    let mut words: Vec<&str> = vec![];
+
+    let tokens = tokens(code);
+    let mut iter = tokens.iter().copied().peekable();
+    while let Some(token) = iter.next() {
+        match token {
+            "{" | "}" | ";" => {
+                if token == "{" {
+                    depth += 1;
+                } else if token == "}" {
+                    depth -= 1;
+                }
+                words.clear();
+                continue;
+            }
+            "package" if depth == 0 => {
+                let mut package = String::new();
+                while let Some(part) = iter.next_if(|t| *t != ";") {
+                    package.push_str(part);
+                }
+                unit.package = Some(package);
+            }
+            "class" | "interface" | "enum" | "record" if depth == 0 => {
+                if let Some(name) = iter.peek() {
+                    if words.contains(&"public") {
+                        unit.public = Some(name.to_string());
+                    }
+                    current = Some((name.to_string(), token == "interface"));
+                }
+            }
+            "main" if depth == 1 && iter.peek() == Some(&"(") && words.last() == Some(&"void") => {
+                if let Some((name, interface)) = &current {
+                    if words.contains(&"static") && (*interface || words.contains(&"public")) {
+                        unit.mains.push(name.clone());
+                    }
+                }
+            }
+            _ => {}
+        }
+        words.push(token);
+    }
+    unit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_main_and_package() {
+        let unit = scan(
+            r#"
+            // package wrong;
+            package com.example . hello;
+
+            import java.util.*;
+
+            class Helper {
+                static void main(String[] args) {}
+            }
+
+            /* public class Nope { public static void main(String[] a) {} } */
+            public final class Hello {
+                private static final String S = "public static void main(";
+                private static final char C = '{';
+                static class Inner { public static void main(String[] args) {} }
+
+                @SuppressWarnings("unused")
+                public static void main(final String... args) {
+                    System.out.println(S + C);
+                }
+            }
+
+            interface Tool {
+                static void main(String[] args) {}
+            }
+            "#,
+        );
+        assert_eq!(
+            unit,
+            Unit {
+                package: Some("com.example.hello".to_owned()),
+                public: Some("Hello".to_owned()),
+                mains: vec!["Hello".to_owned(), "Tool".to_owned()],
+            }
+        );
+        assert_eq!(unit.qualify("Hello"), "com.example.hello.Hello");
+    }
+
+    #[test]
+    fn text_blocks_and_escapes() {
+        let unit = scan(
+            r#"
+            public class Main {
+                static String s = """
+                    }}} "quoted" class Fake {
+                    """;
+                static String t = "\"}";
+                public static void main(String[] args) {}
+            }
+            "#,
+        );
+        assert_eq!(unit.public.as_deref(), Some("Main"));
+        assert_eq!(unit.mains, ["Main"]);
+    }
+}
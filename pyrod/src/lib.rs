@@ -1,7 +1,18 @@
+pub mod java;
 mod run;
 mod service;
+pub mod sql;
+pub use run::is_relative_path;
+pub use run::Artifact;
+pub use run::ArtifactFile;
 pub use run::Language;
+pub use run::Limits;
+pub use run::Repl;
 pub use run::RunError;
+pub use run::RunOutput;
+pub use run::Submission;
+pub use run::MAX_FRAME_LENGTH;
+pub use service::serve;
 pub use service::Pyrod;
 pub use service::PyrodClient;
 pub use service::PyrodServer;
@@ -1,20 +1,52 @@
-use anyhow::Result;
-use pyrod_service::{Pyrod, PyrodServer};
-use tarpc::tokio_serde::formats::Bincode;
-use tarpc::{
-    server::{self, Channel},
-    tokio_util::codec::length_delimited::LengthDelimitedCodec,
-};
-use tokio_vsock::VsockStream;
-use tracing::{Instrument, Level};
+use anyhow::{anyhow, Context, Result};
+use tracing::Level;
 use tracing_subscriber::fmt::format::FmtSpan;
-
-const PORT: u32 = 5000;
+use transport::Transport;
 
 mod init;
+mod transport;
+
+/// Command line options
+/// When running as init in a VM, the kernel passes anything after `--` on its command line
+/// through to us, so these can be set from the VM's boot args too
+#[derive(Debug, Default)]
+struct Args {
+    transport: Transport,
+    /// Don't do PID 1 system setup, for when we're just a normal process
+    no_init: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--transport" => {
+                    parsed.transport = args.next().context("--transport needs a value")?.parse()?;
+                }
+                "--no-init" => parsed.no_init = true,
+                _ => return Err(anyhow!("Unknown argument {arg:?}")),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn main() -> Result<std::process::ExitCode> {
+    //not the agent, just a program languages can run. stdout is the program's so no logging
+    let mut args = std::env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("sql") => return Ok(pyrod_service::sql::main(args.skip(1))),
+        Some("java") => return Ok(pyrod_service::java::main(args.skip(1))),
+        _ => {}
+    }
+
+    agent(args)?;
+    Ok(std::process::ExitCode::SUCCESS)
+}
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn agent(args: impl Iterator<Item = String>) -> Result<()> {
     tracing::subscriber::set_global_default(
         tracing_subscriber::fmt::fmt()
             .with_span_events(FmtSpan::ACTIVE)
@@ -22,25 +54,21 @@ async fn main() -> Result<()> {
             .finish(),
     )?;
 
+    let args = Args::parse(args)?;
+    tracing::info!("Starting with {:?}", args);
+
     //linux system init stuff
-    init::init();
+    if !args.no_init {
+        init::init();
+    }
 
-    //create a new vsock connection
     //we initiate the connection when we're ready
-    let stream = VsockStream::connect(2, PORT).await?;
-    tracing::info!("Vsock connection opened on port {}", PORT);
-
-    //create the serde-based transport layer from the stream
-    //build framed stream from raw one using length delimited codec
-    let transport = tarpc::serde_transport::new(
-        LengthDelimitedCodec::builder().new_framed(stream),
-        Bincode::default(),
-    );
-
-    server::BaseChannel::with_defaults(transport)
-        .execute(PyrodServer.serve())
-        .instrument(tracing::info_span!("RPC Server"))
-        .await;
+    args.transport.serve().await?;
+
+    //host hung up, so we're done
+    if !args.no_init {
+        init::shutdown();
+    }
 
     Ok(())
 }
@@ -0,0 +1,494 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::prelude::OsStringExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{
+    is_relative_path, Artifact, ArtifactFile, Language, Limits, RunError, RunOutput, MAX_ARTIFACT,
+};
+
+/// How long to keep reading output after the process group's been killed
+/// Only something that escaped the group (eg with setsid) can hold the pipes open past then
+const OUTPUT_GRACE: Duration = Duration::from_millis(500);
+
+/// Service user id of untrusted processes - don't want to run as root
+/// Set in the dockerfiles used to build rootfs images
+pub const UNTRUSTED: u32 = 111;
+
+/// Writes the source out, then compiles and runs it with plain commands
+/// Every language goes through here, so all the process handling lives in one place
+#[derive(Debug)]
+pub struct CommandRunner {
+    /// Directory both commands run in
+    pub workdir: PathBuf,
+    /// Where the submission is written
+    pub source: PathBuf,
+    pub compile: Option<Vec<OsString>>,
+    pub run: Vec<OsString>,
+    pub env: BTreeMap<String, String>,
+    /// Where the program writes its structured results, if anywhere
+    pub results: Option<PathBuf>,
+    /// Where a manifest is written, if the language takes one
+    pub manifest: Option<PathBuf>,
+    pub install: Option<Vec<OsString>>,
+    /// What compiling leaves behind that running needs
+    pub artifacts: Vec<PathBuf>,
+    /// The interpreter for sessions, and what to send it for each snippet, if there is one
+    pub repl: Option<(Vec<OsString>, String)>,
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub limits: Limits,
+}
+
+/// What a command did, with its output already capped
+#[derive(Debug)]
+struct Output {
+    status: ExitStatus,
+    stdout: OsString,
+    stderr: OsString,
+    elapsed: Duration,
+}
+
+impl CommandRunner {
+    /// Fill in the command templates from a language definition, to run as the untrusted user
+    pub fn new(lang: &Language, limits: Limits) -> Self {
+        //the source's name can use vars too, eg so an option can pick its extension
+        let mut source = lang.source.to_string_lossy().into_owned();
+        for (var, value) in &lang.vars {
+            source = source.replace(&format!("{{{var}}}"), value);
+        }
+        let source = lang.workdir.join(source);
+        let fill = |template: &str| {
+            let mut filled = template
+                .replace("{source}", &source.to_string_lossy())
+                .replace("{workdir}", &lang.workdir.to_string_lossy());
+            for (var, value) in &lang.vars {
+                filled = filled.replace(&format!("{{{var}}}"), value);
+            }
+            filled
+        };
+        let expand = |template: &Vec<String>| {
+            template
+                .iter()
+                .filter_map(|arg| {
+                    let expanded = fill(arg);
+                    //so a var can be set to nothing to leave out a flag
+                    (arg.is_empty() || !expanded.is_empty()).then(|| expanded.into())
+                })
+                .collect()
+        };
+
+        Self {
+            workdir: lang.workdir.clone(),
+            compile: lang.compile.as_ref().map(expand),
+            run: expand(&lang.run),
+            env: lang.env.iter().map(|(k, v)| (k.clone(), fill(v))).collect(),
+            results: lang.results.clone(),
+            manifest: lang.manifest.as_ref().map(|m| lang.workdir.join(m)),
+            install: lang.install.as_ref().map(expand),
+            artifacts: lang
+                .artifacts
+                .iter()
+                .map(|a| lang.workdir.join(a))
+                .collect(),
+            repl: lang
+                .repl
+                .as_ref()
+                .map(|repl| (expand(&repl.command), fill(&repl.eval))),
+            name: lang.name.clone(),
+            uid: UNTRUSTED,
+            gid: UNTRUSTED,
+            limits,
+            source,
+        }
+    }
+
+    /// Run an install or compile command, with what it printed if it succeeded
+    fn build(&self, argv: &[OsString]) -> Result<OsString, RunError> {
+        let timeout = self.limits.compile_timeout;
+        let output = self
+            .exec(argv, None, timeout)?
+            .ok_or(RunError::CompileTimeout(timeout))?;
+
+        if output.status.success() {
+            tracing::info!("Code compiled succesfully in {:?}", output.elapsed);
+            //warnings and the like, we don't care which stream they were on
+            let mut printed = output.stdout;
+            printed.push(output.stderr);
+            Ok(printed)
+        } else {
+            tracing::error!("Code failed to compile after {:?}", output.elapsed);
+            Err(RunError::CompileError(output.stdout, output.stderr))
+        }
+    }
+
+    /// Run a command to completion, or until it times out (None)
+    fn exec(
+        &self,
+        argv: &[OsString],
+        stdin: Option<String>,
+        timeout: Duration,
+    ) -> Result<Option<Output>, RunError> {
+        let (program, args) = argv
+            .split_first()
+            .ok_or_else(|| RunError::IOError("Empty command".to_owned()))?;
+
+        let start = Instant::now();
+        let mut child = Command::new(program)
+            .args(args)
+            .current_dir(&self.workdir)
+            .envs(&self.env)
+            .uid(self.uid)
+            .gid(self.gid)
+            .process_group(0) //so we can kill anything it forks too
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        tracing::debug!("Child process spawned");
+
+        //feed input and collect output on their own threads
+        //so a program that writes a lot before it's read all its input can't deadlock with us
+        let writer = child.stdin.take().zip(stdin).map(|(mut pipe, input)| {
+            thread::spawn(move || {
+                //if it exits without reading all of it that's its problem
+                let _ = pipe.write_all(input.as_bytes());
+            })
+        });
+        let max = self.limits.max_output;
+        let stdout = child.stdout.take().map(|p| Reader::spawn(p, max));
+        let stderr = child.stderr.take().map(|p| Reader::spawn(p, max));
+
+        let status = wait_timeout(&mut child, timeout);
+
+        //kill whatever's left in the group, including anything backgrounded that still holds the pipes open
+        //SAFETY - just a syscall
+        unsafe { libc::kill(-(child.id() as i32), libc::SIGKILL) };
+        let Some(status) = status? else {
+            child.wait()?;
+            return Ok(None);
+        };
+
+        //it's either done, or stuck on something that escaped the group and isn't reading
+        drop(writer);
+
+        let deadline = Instant::now() + OUTPUT_GRACE;
+        Ok(Some(Output {
+            status,
+            stdout: finish(stdout, deadline)?,
+            stderr: finish(stderr, deadline)?,
+            elapsed: start.elapsed(),
+        }))
+    }
+
+    /// Write the submission's other files out, where they go in the working directory
+    fn write_files(&self, files: BTreeMap<PathBuf, String>) -> Result<(), RunError> {
+        for (path, contents) in files {
+            if !is_relative_path(&path) {
+                return Err(RunError::BadPath(path));
+            }
+            let path = self.workdir.join(path);
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, contents)?;
+        }
+        Ok(())
+    }
+
+    /// Results are all or nothing, cutting JSON off halfway would just make it invalid
+    fn read_results(&self, path: &Path, stderr: &mut OsString) -> Result<Option<String>, RunError> {
+        //the program could have swapped it for a link to something it shouldn't be able to read,
+        //or a FIFO that opening would wait on forever
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+            .open(path);
+        let file = match file {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if !file.metadata()?.is_file() {
+            stderr.push("\n[results aren't a regular file, dropped]");
+            return Ok(None);
+        }
+
+        let max = self.limits.max_output;
+        let mut results = String::new();
+        file.take(max as u64 + 1)
+            .read_to_string(&mut results)
+            .map_err(|_| RunError::OutputUtf8Error)?;
+        if results.len() > max {
+            stderr.push(format!("\n[results over {max} bytes dropped]"));
+            return Ok(None);
+        }
+        Ok(Some(results))
+    }
+}
+
+impl super::Runner for CommandRunner {
+    //write the code (and any other files) out, then install the manifest and compile it if the language needs it
+    #[tracing::instrument(skip(code, files, manifest))]
+    fn compile(
+        &self,
+        code: String,
+        files: BTreeMap<PathBuf, String>,
+        manifest: Option<String>,
+    ) -> Result<OsString, RunError> {
+        self.write_files(files)?;
+
+        if let Some(dir) = self.source.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.source, code)?;
+        tracing::debug!("Code written out to {:?}", self.source);
+
+        let mut printed = OsString::new();
+        if let Some(manifest) = manifest {
+            let path = self
+                .manifest
+                .as_ref()
+                .ok_or_else(|| RunError::NoManifest(self.name.clone()))?;
+            std::fs::write(path, manifest)?;
+            tracing::debug!("Manifest written out to {path:?}");
+
+            if let Some(install) = &self.install {
+                printed = self.build(install)?;
+            }
+        }
+
+        if let Some(compile) = &self.compile {
+            printed.push(self.build(compile)?);
+        }
+        Ok(printed)
+    }
+
+    #[tracing::instrument(skip(self, stdin))]
+    fn run(&self, stdin: String) -> Result<RunOutput, RunError> {
+        if let Some(results) = &self.results {
+            let _ = std::fs::remove_file(results);
+        }
+
+        let timeout = self.limits.run_timeout;
+        let output = self
+            .exec(&self.run, Some(stdin), timeout)?
+            .ok_or(RunError::RunTimeout(timeout))?;
+
+        tracing::info!("Ran in {:?}, exited with {}", output.elapsed, output.status);
+
+        let mut stderr = output.stderr;
+        let results = match &self.results {
+            Some(path) => self.read_results(path, &mut stderr)?,
+            None => None,
+        };
+
+        Ok(RunOutput {
+            stdout: output.stdout,
+            stderr,
+            results,
+            ..Default::default()
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn artifact(&self) -> Result<Option<Artifact>, RunError> {
+        if self.artifacts.is_empty() {
+            return Ok(None);
+        }
+
+        let mut artifact = Artifact::default();
+        for path in &self.artifacts {
+            //if compiling didn't make it there's nothing worth keeping
+            if !collect(path, &mut artifact)? || artifact.size() > MAX_ARTIFACT {
+                tracing::info!(
+                    "Not sending back artifacts, {path:?} is missing or they're too big"
+                );
+                return Ok(None);
+            }
+        }
+        tracing::debug!("Collected {} bytes of artifacts", artifact.size());
+        Ok(Some(artifact))
+    }
+
+    #[tracing::instrument(skip(self, artifact, files))]
+    fn restore(
+        &self,
+        artifact: Artifact,
+        files: BTreeMap<PathBuf, String>,
+    ) -> Result<(), RunError> {
+        self.write_files(files)?;
+
+        for file in artifact.files {
+            //only ever somewhere the language said artifacts go
+            let allowed = self.artifacts.iter().any(|a| file.path.starts_with(a))
+                && !file
+                    .path
+                    .components()
+                    .any(|c| c == std::path::Component::ParentDir);
+            if !allowed {
+                return Err(RunError::BadPath(file.path));
+            }
+            if let Some(dir) = file.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&file.path, &file.contents)?;
+            std::fs::set_permissions(&file.path, std::fs::Permissions::from_mode(file.mode))?;
+            //the same as if the untrusted user had compiled it
+            std::os::unix::fs::chown(&file.path, Some(self.uid), Some(self.gid))?;
+        }
+        tracing::debug!("Artifacts restored");
+        Ok(())
+    }
+}
+
+/// Add a file, or everything in a directory, to an artifact. False if it's not there
+/// Links and anything else that isn't a plain file are left out, they could point anywhere
+fn collect(path: &Path, artifact: &mut Artifact) -> Result<bool, RunError> {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    if meta.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            collect(&entry?.path(), artifact)?;
+        }
+    } else if meta.is_file() {
+        let mut contents = vec![];
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)?
+            .take(MAX_ARTIFACT as u64 + 1)
+            .read_to_end(&mut contents)?;
+        artifact.files.push(ArtifactFile {
+            path: path.to_owned(),
+            mode: meta.permissions().mode() & 0o777,
+            contents,
+        });
+    }
+    Ok(true)
+}
+
+/// Poll for the child to exit until the timeout runs out
+fn wait_timeout(child: &mut Child, timeout: Duration) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// What's been read from one of a process's pipes so far, up to the output limit
+#[derive(Debug, Default)]
+struct Captured {
+    kept: Vec<u8>,
+    dropped: usize,
+}
+
+/// Reads a pipe on its own thread until it closes, keeping what it's read where it can be taken
+/// even if the pipe never closes
+struct Reader {
+    captured: Arc<Mutex<Captured>>,
+    done: Receiver<std::io::Result<()>>,
+}
+
+impl Reader {
+    fn spawn(mut pipe: impl Read + Send + 'static, max: usize) -> Reader {
+        let captured = Arc::new(Mutex::new(Captured::default()));
+        let (tx, done) = mpsc::channel();
+        let shared = captured.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 8192];
+            let read = loop {
+                let n = match pipe.read(&mut buf) {
+                    Ok(0) => break Ok(()),
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => break Err(e),
+                };
+                let mut captured = shared.lock().unwrap();
+                let keep = n.min(max.saturating_sub(captured.kept.len()));
+                captured.kept.extend_from_slice(&buf[..keep]);
+                captured.dropped += n - keep;
+            };
+            let _ = tx.send(read);
+        });
+        Reader { captured, done }
+    }
+}
+
+/// Wait until `deadline` for a pipe to close, then take what's been read from it
+/// If it's still open the reader's left to it, it'll go when whatever has the pipe does
+fn finish(reader: Option<Reader>, deadline: Instant) -> Result<OsString, RunError> {
+    let Some(reader) = reader else {
+        return Ok(OsString::new());
+    };
+    let mut note = None;
+    match reader
+        .done
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+    {
+        Ok(read) => read?,
+        Err(RecvTimeoutError::Timeout) => {
+            tracing::warn!("Output still open after the process group was killed");
+            note = Some("\n[output still open after exiting, stopped reading]".to_owned());
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            return Err(RunError::ThreadPanicked(
+                "Output reader panicked".to_owned(),
+            ))
+        }
+    }
+
+    let captured = std::mem::take(&mut *reader.captured.lock().unwrap());
+    let mut bytes = captured.kept;
+    let mut dropped = captured.dropped;
+    if dropped > 0 {
+        let keep = utf8_boundary(&bytes);
+        dropped += bytes.len() - keep;
+        bytes.truncate(keep);
+        bytes.extend(format!("\n[{dropped} bytes truncated]").into_bytes());
+    }
+    bytes.extend(note.unwrap_or_default().into_bytes());
+    Ok(OsString::from_vec(bytes))
+}
+
+/// How much of output that's been cut off to keep, so it doesn't end partway through a UTF-8 character
+/// Anything that isn't UTF-8 anyway is left as it is
+pub(super) fn utf8_boundary(bytes: &[u8]) -> usize {
+    //a character is at most 4 bytes, so the last one started in the last 4
+    for back in 1..=bytes.len().min(4) {
+        let at = bytes.len() - back;
+        let width = match bytes[at] {
+            //continuation byte, keep looking for the start
+            0x80..=0xBF => continue,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        return if width > back { at } else { bytes.len() };
+    }
+    bytes.len()
+}
@@ -1,57 +1,156 @@
-mod bash;
-mod java;
-mod python;
-mod rust;
-mod sh;
+mod command;
+mod session;
 
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::{ffi::OsString, time::Duration};
 use thiserror::Error;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
-pub enum Language {
-    Python,
-    Rust,
-    Java,
-    Bash,
-    Sh,
+pub use command::CommandRunner;
+pub use session::Session;
+
+/// Limits for a single run, which the host sets per language
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub struct Limits {
+    pub compile_timeout: Duration,
+    pub run_timeout: Duration,
+    /// stdout and stderr are each cut off after this many bytes
+    pub max_output: usize,
 }
 
-pub trait Runner: Send + Sync {
-    fn compile(&self, code: String) -> Result<(), RunError>;
-    fn run(&self, stdin: String) -> Result<(OsString, OsString), RunError>;
+/// How to compile and run code in a language, sent over by the host with each run
+/// Commands are argv templates, where `{source}` and `{workdir}` are replaced with the
+/// path of the source file and the working directory, and `{<var>}` with anything in `vars`.
+/// Arguments that end up empty are dropped.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Language {
+    pub name: String,
+    /// Directory the code is compiled and run in
+    pub workdir: PathBuf,
+    /// Where to write the submission, relative to `workdir`
+    pub source: PathBuf,
+    /// Command to compile the source, if it needs compiling
+    pub compile: Option<Vec<String>>,
+    /// Command to run the (compiled) program
+    pub run: Vec<String>,
+    /// Extra environment variables for both commands, filled in the same as the commands
+    pub env: BTreeMap<String, String>,
+    /// Values to fill in the command templates with, eg compiler flags
+    pub vars: BTreeMap<String, String>,
+    /// File the program writes structured (JSON) results to, which are sent back with its output
+    pub results: Option<PathBuf>,
+    /// Where to write the submission's manifest (eg `requirements.txt`), relative to `workdir`,
+    /// for languages that can install dependencies from one
+    pub manifest: Option<PathBuf>,
+    /// Command to install the manifest's dependencies, only run when a submission has a manifest.
+    /// It counts as compiling, so it fails the same way
+    pub install: Option<Vec<String>>,
+    /// What compiling leaves behind, paths relative to `workdir` (or absolute), files or directories
+    /// It's everything running needs, so the host can keep it and not compile the same code again
+    pub artifacts: Vec<PathBuf>,
+    /// How to keep an interpreter running for a session, for languages that have a REPL
+    pub repl: Option<Repl>,
 }
 
-impl Language {
-    pub fn get_runner(self) -> &'static dyn Runner {
-        //we leak these because we need them to be passed between threads
-        //they're zero-sized types anyway and we don't need the process
-        //to be around for long
-        match self {
-            Language::Python => Box::leak(Box::new(python::PythonRunner)),
-            Language::Rust => Box::leak(Box::new(rust::RustRunner)),
-            Language::Java => Box::leak(Box::new(java::JavaRunner)),
-            Language::Bash => Box::leak(Box::new(bash::BashRunner)),
-            Language::Sh => Box::leak(Box::new(sh::ShRunner)),
-        }
+/// An interpreter that's started once, and fed snippets of code one at a time
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Repl {
+    /// Filled in like the other commands, and run in the working directory
+    pub command: Vec<String>,
+    /// Sent to the interpreter's stdin for each snippet, after it's been written to the source file.
+    /// It has to print `{marker}` on a line of its own to stdout and stderr once the snippet's done,
+    /// even if it failed
+    pub eval: String,
+}
+
+/// What a request wants run
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Submission {
+    pub code: String,
+    /// Any other files to write next to the source, by path relative to the working directory
+    pub files: BTreeMap<PathBuf, String>,
+    /// For languages that install dependencies from a manifest, like `requirements.txt`
+    pub manifest: Option<String>,
+    pub input: String,
+    /// Send back the language's artifacts after compiling, so the host can cache them
+    pub return_artifact: bool,
+}
+
+/// The artifacts from compiling some code, with absolute paths
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Artifact {
+    pub files: Vec<ArtifactFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ArtifactFile {
+    pub path: PathBuf,
+    /// Permission bits, so executables stay executable
+    pub mode: u32,
+    pub contents: Vec<u8>,
+}
+
+impl Artifact {
+    pub fn size(&self) -> usize {
+        self.files.iter().map(|f| f.contents.len()).sum()
     }
 }
 
+/// Artifacts bigger than this aren't sent back, so they always fit in a frame
+pub const MAX_ARTIFACT: usize = 32 * 1024 * 1024;
+/// The biggest RPC message either end accepts
+pub const MAX_FRAME_LENGTH: usize = 2 * MAX_ARTIFACT;
+
+/// What came out of running some code
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct RunOutput {
+    pub stdout: OsString,
+    pub stderr: OsString,
+    /// Anything the compiler printed, like warnings, or the errors if it failed
+    pub compile_output: OsString,
+    /// Compiling failed, so `compile_output` has the errors and the code wasn't run
+    pub compile_error: bool,
+    /// What the program left in the language's results file, if it has one
+    pub results: Option<String>,
+    /// How long compiling took, if the language compiles
+    pub compile_time: Option<Duration>,
+    /// How long the program ran for, unless it didn't compile
+    pub run_time: Option<Duration>,
+    /// What compiling made, if it was asked for and the language has artifacts
+    pub artifact: Option<Artifact>,
+}
+
+/// Somewhere under the working directory, without going up out of it
+pub fn is_relative_path(path: &std::path::Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+}
+
 impl std::fmt::Display for Language {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Language::Python => "python",
-                Language::Rust => "rust",
-                Language::Java => "java",
-                Language::Bash => "bash",
-                Language::Sh => "sh",
-            }
-        )
+        write!(f, "{}", self.name)
     }
 }
 
+pub trait Runner: Send + Sync {
+    /// Returns whatever the compiler printed, if it succeeded
+    /// `files` are written next to the source first, by their paths relative to the working directory
+    fn compile(
+        &self,
+        code: String,
+        files: BTreeMap<PathBuf, String>,
+        manifest: Option<String>,
+    ) -> Result<OsString, RunError>;
+    fn run(&self, stdin: String) -> Result<RunOutput, RunError>;
+    /// Collect the language's artifacts after compiling, or None if it hasn't got any
+    fn artifact(&self) -> Result<Option<Artifact>, RunError>;
+    /// Put back what an earlier compile made instead of compiling, along with the other files
+    fn restore(&self, artifact: Artifact, files: BTreeMap<PathBuf, String>)
+        -> Result<(), RunError>;
+}
+
 #[derive(Debug, Error, serde::Deserialize, serde::Serialize)]
 pub enum RunError {
     #[error("Thread panicked during execution: {0}")]
@@ -70,6 +169,18 @@ pub enum RunError {
     RunTimeout(Duration),
     #[error("Code exceeded max compilation time of {0:?}")]
     CompileTimeout(Duration),
+    #[error("{0} doesn't take a manifest")]
+    NoManifest(String),
+    #[error(
+        "Can't write a file to {0:?}, it has to be a relative path inside the working directory"
+    )]
+    BadPath(PathBuf),
+    #[error("{0} doesn't have a REPL")]
+    NoRepl(String),
+    #[error("There's no session open")]
+    NoSession,
+    #[error("The session's interpreter exited")]
+    SessionEnded,
 }
 
 impl From<std::io::Error> for RunError {
@@ -0,0 +1,246 @@
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::os::unix::prelude::OsStringExt;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Instant;
+
+use super::command::utf8_boundary;
+use super::{CommandRunner, RunError, RunOutput};
+
+const STDOUT: usize = 0;
+const STDERR: usize = 1;
+
+/// A REPL that's kept running between snippets, so each one sees what the ones before it left behind
+/// The interpreter runs as the untrusted user like any other command, and is killed when this is dropped
+#[derive(Debug)]
+pub struct Session {
+    child: Child,
+    stdin: ChildStdin,
+    /// Chunks of stdout and stderr as they're read, and an empty chunk when either closes
+    output: Receiver<(usize, Vec<u8>)>,
+    runner: CommandRunner,
+    /// What's sent for each snippet, with everything but the marker filled in
+    eval: String,
+}
+
+impl Session {
+    /// Start the language's interpreter
+    pub fn open(runner: CommandRunner) -> Result<Session, RunError> {
+        let (command, eval) = runner
+            .repl
+            .clone()
+            .ok_or_else(|| RunError::NoRepl(runner.name.clone()))?;
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| RunError::IOError("Empty command".to_owned()))?;
+
+        std::fs::create_dir_all(&runner.workdir)?;
+        let mut child = Command::new(program)
+            .args(args)
+            .current_dir(&runner.workdir)
+            .envs(&runner.env)
+            .uid(runner.uid)
+            .gid(runner.gid)
+            .process_group(0) //so we can kill anything it forks too
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        tracing::debug!("REPL spawned");
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let (tx, output) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            forward(STDOUT, stdout, tx.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward(STDERR, stderr, tx);
+        }
+
+        Ok(Session {
+            child,
+            stdin,
+            output,
+            runner,
+            eval,
+        })
+    }
+
+    /// Run a snippet, and collect what it printed up to the marker
+    /// A session that times out or whose interpreter exits can't be used again
+    #[tracing::instrument(skip(self, code))]
+    pub fn eval(&mut self, code: String) -> Result<RunOutput, RunError> {
+        //anything a snippet left running printed after it was done
+        loop {
+            match self.output.try_recv() {
+                Ok((_, chunk)) if chunk.is_empty() => return Err(RunError::SessionEnded),
+                Ok(_) => {}
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => return Err(RunError::SessionEnded),
+            }
+        }
+
+        if let Some(dir) = self.runner.source.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.runner.source, code)?;
+
+        let marker = marker()?;
+        let mut command = self.eval.replace("{marker}", &marker);
+        if !command.ends_with('\n') {
+            command.push('\n');
+        }
+        let start = Instant::now();
+        self.stdin
+            .write_all(command.as_bytes())
+            .and_then(|_| self.stdin.flush())
+            .map_err(|_| RunError::SessionEnded)?;
+
+        let timeout = self.runner.limits.run_timeout;
+        let max = self.runner.limits.max_output;
+        let deadline = start + timeout;
+        let needle = format!("{marker}\n").into_bytes();
+        let mut streams = [Collected::default(), Collected::default()];
+        while !streams.iter().all(|s| s.done) {
+            match self
+                .output
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok((_, chunk)) if chunk.is_empty() => return Err(RunError::SessionEnded),
+                Ok((stream, chunk)) => streams[stream].push(&chunk, &needle, max),
+                Err(RecvTimeoutError::Timeout) => return Err(RunError::RunTimeout(timeout)),
+                Err(RecvTimeoutError::Disconnected) => return Err(RunError::SessionEnded),
+            }
+        }
+
+        let [stdout, stderr] = streams;
+        Ok(RunOutput {
+            stdout: stdout.finish(max),
+            stderr: stderr.finish(max),
+            run_time: Some(start.elapsed()),
+            ..Default::default()
+        })
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        //SAFETY - just a syscall
+        unsafe { libc::kill(-(self.child.id() as i32), libc::SIGKILL) };
+        let _ = self.child.wait();
+        tracing::debug!("REPL killed");
+    }
+}
+
+/// Send everything read from a pipe down the channel, then an empty chunk when it closes
+fn forward(stream: usize, mut pipe: impl Read + Send + 'static, tx: Sender<(usize, Vec<u8>)>) {
+    thread::spawn(move || {
+        let mut buf = [0; 8192];
+        loop {
+            let n = match pipe.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => 0,
+            };
+            //or the session's gone
+            if tx.send((stream, buf[..n].to_vec())).is_err() || n == 0 {
+                return;
+            }
+        }
+    });
+}
+
+/// Something a snippet can't print by accident, so we know when it's done
+fn marker() -> Result<String, RunError> {
+    let mut bytes = [0; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// What a snippet printed to one stream, up to the marker
+#[derive(Debug, Default)]
+struct Collected {
+    bytes: Vec<u8>,
+    dropped: usize,
+    done: bool,
+}
+
+impl Collected {
+    /// Add what's been read, stopping at the marker
+    /// Past the first `max` bytes, only enough is kept to find the marker in
+    fn push(&mut self, chunk: &[u8], needle: &[u8], max: usize) {
+        if self.done {
+            return;
+        }
+        //it could have been split across chunks
+        let from = self.bytes.len().saturating_sub(needle.len());
+        self.bytes.extend_from_slice(chunk);
+        if let Some(at) = self.bytes[from..]
+            .windows(needle.len())
+            .position(|w| w == needle)
+        {
+            self.bytes.truncate(from + at);
+            self.done = true;
+            return;
+        }
+        let spare = self.bytes.len().saturating_sub(max + needle.len());
+        if spare > 0 {
+            self.bytes.drain(max..max + spare);
+            self.dropped += spare;
+        }
+    }
+
+    /// Cut down to `max` bytes, noting how much was dropped the same as for a run
+    fn finish(mut self, max: usize) -> OsString {
+        if self.bytes.len() > max {
+            self.dropped += self.bytes.len() - max;
+            self.bytes.truncate(max);
+        }
+        if self.dropped > 0 {
+            let keep = utf8_boundary(&self.bytes);
+            self.dropped += self.bytes.len() - keep;
+            self.bytes.truncate(keep);
+            let note = format!("\n[{} bytes truncated]", self.dropped);
+            self.bytes.extend(note.into_bytes());
+        }
+        OsString::from_vec(self.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_up_to_the_marker() {
+        let needle = b"m4rk3r\n";
+        let mut out = Collected::default();
+        out.push(b"hello", needle, 8);
+        out.push(b" world, this is too long m4r", needle, 8);
+        assert!(!out.done);
+        out.push(b"k3r\nafter", needle, 8);
+        assert!(out.done);
+        out.push(b"ignored", needle, 8);
+        assert_eq!(out.finish(8), "hello wo\n[22 bytes truncated]");
+
+        //the marker doesn't have to start a line
+        let mut out = Collected::default();
+        out.push(b"no newline m4rk3r\n", needle, 100);
+        assert_eq!(out.finish(100), "no newline ");
+    }
+
+    #[test]
+    fn cut_between_characters() {
+        let needle = b"m4rk3r\n";
+        let mut out = Collected::default();
+        out.push("héllo".as_bytes(), needle, 2);
+        assert_eq!(out.finish(2), "h\n[5 bytes truncated]");
+
+        assert_eq!(utf8_boundary("ab€".as_bytes()), 5);
+        assert_eq!(utf8_boundary(&"ab€".as_bytes()[..4]), 2);
+        assert_eq!(utf8_boundary(&[0x80, 0x80, 0x80, 0x80]), 4);
+    }
+}
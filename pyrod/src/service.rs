@@ -1,9 +1,20 @@
-use std::{ffi::OsString, time::Duration};
+use crate::run::{
+    Artifact, CommandRunner, Limits, RunError, RunOutput, Runner, Session, Submission,
+    MAX_FRAME_LENGTH,
+};
 
-use crate::run::RunError;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tarpc::tokio_serde::formats::Bincode;
+use tarpc::{
+    context,
+    server::{self, Channel},
+    tokio_util::codec::length_delimited::LengthDelimitedCodec,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::spawn_blocking;
+use tracing::Instrument;
 
-use tarpc::context;
-use tokio::{task::spawn_blocking, time::timeout};
 // This is the service definition. It looks a lot like a trait definition.
 // It defines one RPC, hello, which takes one arg, name, and returns a String.
 #[tarpc::service]
@@ -13,14 +24,35 @@ pub trait Pyrod {
 
     async fn run_code(
         lang: super::run::Language,
-        code: String,
-        input: String,
-        timeouts: (Duration, Duration),
-    ) -> Result<(OsString, OsString), RunError>;
+        submission: Submission,
+        limits: Limits,
+    ) -> Result<RunOutput, RunError>;
+
+    /// Run code that's already been compiled, from the artifacts an earlier run sent back
+    /// The submission's code isn't used, but its files and input are
+    async fn run_precompiled(
+        lang: super::run::Language,
+        artifact: Artifact,
+        submission: Submission,
+        limits: Limits,
+    ) -> Result<RunOutput, RunError>;
+
+    /// Start the language's REPL, which keeps running for `eval` until it's closed or the host hangs up
+    /// There's one session at a time, so opening another closes the last
+    async fn open_session(lang: super::run::Language, limits: Limits) -> Result<(), RunError>;
+
+    /// Run a snippet in the open session, with whatever the earlier ones left behind
+    /// Each gets the language's run timeout, and if it runs out (or the interpreter exits) the session's over
+    async fn eval(code: String) -> Result<RunOutput, RunError>;
+
+    async fn close_session();
 }
 
-#[derive(Clone, Debug)]
-pub struct PyrodServer;
+/// One for each connection, which is the host's for as long as the VM's up
+#[derive(Clone, Debug, Default)]
+pub struct PyrodServer {
+    session: Arc<Mutex<Option<Session>>>,
+}
 
 #[tarpc::server]
 impl Pyrod for PyrodServer {
@@ -34,33 +66,129 @@ impl Pyrod for PyrodServer {
         self,
         _: context::Context,
         lang: super::run::Language,
-        code: String,
-        input: String,
-        (compile_timeout, run_timeout): (Duration, Duration),
-    ) -> Result<(OsString, OsString), RunError> {
-        let runner = lang.get_runner();
+        submission: Submission,
+        limits: Limits,
+    ) -> Result<RunOutput, RunError> {
+        let runner = CommandRunner::new(&lang, limits);
+        let compiles =
+            lang.compile.is_some() || (lang.install.is_some() && submission.manifest.is_some());
 
         //there's no point making these async, because all they're doing
         //is a bit of filesystem stuff and calling other processes
         //which is not something we need to do asynchronously
         //but we do need to spawn_blocking because function colours
+        //timeouts and output caps are handled by the runner
+        spawn_blocking(move || {
+            let start = Instant::now();
+            let compiled = runner.compile(submission.code, submission.files, submission.manifest);
+            let compile_time = compiles.then(|| start.elapsed());
+            let compile_output = match compiled {
+                //if we get a compile error can return early with an okay (skill issue error)
+                Err(RunError::CompileError(stdout, stderr)) => {
+                    tracing::info!("Compilation error: stdout: {stdout:?}, stderr: {stderr:?}");
+                    //the same as warnings, we don't care which stream they were on
+                    let mut compile_output = stdout;
+                    compile_output.push(stderr);
+                    return Ok(RunOutput {
+                        compile_output,
+                        compile_error: true,
+                        compile_time,
+                        ..Default::default()
+                    });
+                }
+                //return any unexpected errors we got
+                other => other?,
+            };
+            //before running, which could change them
+            let artifact = match submission.return_artifact {
+                true => runner.artifact()?,
+                false => None,
+            };
 
-        let compile_task = timeout(compile_timeout, spawn_blocking(|| runner.compile(code)))
-            .await
-            //handle compile timeout
-            //other errors handled for us
-            .map_err(|_| RunError::CompileTimeout(compile_timeout))??;
-
-        //if we get a compile error can return early with an okay (skill issue error)
-        if let Err(RunError::CompileError(out, err)) = compile_task {
-            tracing::info!("Compilation error: stdout: {:?}, stderr: {:?}", out, err);
-            return Ok((out, err));
-        }
-        //return any unexpected errors we got
-        compile_task?;
-
-        timeout(run_timeout, spawn_blocking(|| runner.run(input)))
-            .await
-            .map_err(|_| RunError::RunTimeout(run_timeout))??
+            let start = Instant::now();
+            let output = runner.run(submission.input)?;
+            Ok(RunOutput {
+                compile_output,
+                compile_time,
+                run_time: Some(start.elapsed()),
+                artifact,
+                ..output
+            })
+        })
+        .await?
     }
+
+    async fn run_precompiled(
+        self,
+        _: context::Context,
+        lang: super::run::Language,
+        artifact: Artifact,
+        submission: Submission,
+        limits: Limits,
+    ) -> Result<RunOutput, RunError> {
+        let runner = CommandRunner::new(&lang, limits);
+        spawn_blocking(move || {
+            runner.restore(artifact, submission.files)?;
+            let start = Instant::now();
+            let output = runner.run(submission.input)?;
+            Ok(RunOutput {
+                run_time: Some(start.elapsed()),
+                ..output
+            })
+        })
+        .await?
+    }
+
+    #[tracing::instrument(skip(self, _ctx, lang), fields(lang = %lang))]
+    async fn open_session(
+        self,
+        _ctx: context::Context,
+        lang: super::run::Language,
+        limits: Limits,
+    ) -> Result<(), RunError> {
+        let runner = CommandRunner::new(&lang, limits);
+        spawn_blocking(move || {
+            //the old one's killed first
+            let mut session = self.session.lock().unwrap();
+            *session = None;
+            *session = Some(Session::open(runner)?);
+            Ok(())
+        })
+        .await?
+    }
+
+    #[tracing::instrument(skip(self, _ctx, code))]
+    async fn eval(self, _ctx: context::Context, code: String) -> Result<RunOutput, RunError> {
+        spawn_blocking(move || {
+            let mut session = self.session.lock().unwrap();
+            let output = session.as_mut().ok_or(RunError::NoSession)?.eval(code);
+            if let Err(RunError::RunTimeout(_) | RunError::SessionEnded) = output {
+                *session = None;
+            }
+            output
+        })
+        .await?
+    }
+
+    #[tracing::instrument(skip(self, _ctx))]
+    async fn close_session(self, _ctx: context::Context) {
+        let _ = spawn_blocking(move || *self.session.lock().unwrap() = None).await;
+    }
+}
+
+/// Serve RPC calls over an already-connected stream until the other end hangs up
+pub async fn serve<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) {
+    //create the serde-based transport layer from the stream
+    //build framed stream from raw one using length delimited codec
+    let transport = tarpc::serde_transport::new(
+        LengthDelimitedCodec::builder()
+            .max_frame_length(MAX_FRAME_LENGTH)
+            .new_framed(stream),
+        Bincode::default(),
+    );
+
+    server::BaseChannel::with_defaults(transport)
+        .execute(PyrodServer::default().serve())
+        .instrument(tracing::info_span!("RPC Server"))
+        .await;
 }
@@ -0,0 +1,293 @@
+//! `pyrod sql`, which runs a SQL script against SQLite for the sql language
+//! It's run as the language's run command like any other program, so it gets the same
+//! untrusted user, timeouts and output caps. pyrod is in every image anyway.
+
+use anyhow::{anyhow, bail, Context, Result};
+use rusqlite::types::ValueRef;
+use rusqlite::{Batch, Connection};
+use serde_json::{json, Value};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// One statement's worth of rows
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    /// Directory of `<name>.db` fixture databases
+    fixtures: Option<PathBuf>,
+    /// Where to write the result sets as JSON
+    results: Option<PathBuf>,
+    script: PathBuf,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut parsed = Args::default();
+        let mut script = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--fixtures" => {
+                    parsed.fixtures = Some(args.next().context("--fixtures needs a value")?.into())
+                }
+                "--results" => {
+                    parsed.results = Some(args.next().context("--results needs a value")?.into())
+                }
+                _ if script.is_none() => script = Some(arg.into()),
+                _ => bail!("Unknown argument {arg:?}"),
+            }
+        }
+        parsed.script =
+            script.context("Usage: pyrod sql [--fixtures <dir>] [--results <file>] <script>")?;
+        Ok(parsed)
+    }
+}
+
+/// Run the script, with stdin as either the name of a fixture database or a setup script
+/// Each result set is printed as a table, and written out as JSON if asked
+/// Errors are printed to stderr and exit non-zero, after any results before the error
+pub fn main(args: impl Iterator<Item = String>) -> std::process::ExitCode {
+    match run(args) {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: impl Iterator<Item = String>) -> Result<()> {
+    let args = Args::parse(args)?;
+    let script = std::fs::read_to_string(&args.script)
+        .with_context(|| format!("Could not read {:?}", args.script))?;
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input)?;
+
+    let conn = open(&input, args.fixtures.as_deref())?;
+
+    let mut results = vec![];
+    let outcome = execute(&conn, &script, |set| {
+        print!("{}", table(&set));
+        results.push(set);
+    });
+
+    //write out whatever we got, even if a later statement failed
+    if let Some(path) = args.results {
+        std::fs::write(&path, serde_json::to_string(&results)?)
+            .with_context(|| format!("Could not write results to {path:?}"))?;
+    }
+    outcome
+}
+
+/// A single word is the name of a fixture, anything else is SQL to set the database up with
+fn open(input: &str, fixtures: Option<&Path>) -> Result<Connection> {
+    let input = input.trim();
+    let is_name = !input.is_empty()
+        && input
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if !is_name {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(input).context("Setup script failed")?;
+        return Ok(conn);
+    }
+
+    let fixture = fixtures.map(|dir| dir.join(format!("{input}.db")));
+    match fixture {
+        Some(path) if path.is_file() => {
+            //work on a copy, so the script can change it
+            let copy = Path::new(input).with_extension("db");
+            std::fs::copy(&path, &copy)
+                .with_context(|| format!("Could not copy fixture {path:?}"))?;
+            Ok(Connection::open(copy)?)
+        }
+        _ => {
+            let available = fixtures
+                .and_then(|dir| std::fs::read_dir(dir).ok())
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| {
+                    let path = entry.ok()?.path();
+                    (path.extension()? == "db")
+                        .then(|| path.file_stem()?.to_str().map(String::from))?
+                })
+                .collect::<Vec<_>>();
+            Err(anyhow!(
+                "No fixture database called {input:?}, there's: {}",
+                available.join(", ")
+            ))
+        }
+    }
+}
+
+/// Run each statement in turn, handing over the rows of the ones that return any columns
+pub fn execute(
+    conn: &Connection,
+    script: &str,
+    mut on_result: impl FnMut(ResultSet),
+) -> Result<()> {
+    let mut batch = Batch::new(conn, script);
+    let mut n = 0;
+    while let Some(mut stmt) = batch
+        .next()
+        .with_context(|| format!("Statement {}", n + 1))?
+    {
+        n += 1;
+        let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+        if columns.is_empty() {
+            stmt.raw_execute()
+                .with_context(|| format!("Statement {n}"))?;
+            continue;
+        }
+
+        let mut rows = vec![];
+        let mut query = stmt.raw_query();
+        while let Some(row) = query.next().with_context(|| format!("Statement {n}"))? {
+            rows.push(
+                (0..columns.len())
+                    .map(|i| row.get_ref(i).map(to_json))
+                    .collect::<rusqlite::Result<_>>()?,
+            );
+        }
+        on_result(ResultSet { columns, rows });
+    }
+    Ok(())
+}
+
+fn to_json(value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => json!(i),
+        ValueRef::Real(f) => json!(f),
+        ValueRef::Text(t) => json!(String::from_utf8_lossy(t)),
+        //the same as a blob literal in SQL
+        ValueRef::Blob(b) => json!(format!(
+            "X'{}'",
+            b.iter().map(|b| format!("{b:02X}")).collect::<String>()
+        )),
+    }
+}
+
+/// Format a result set like the sqlite3 CLI's table mode, with a row count under it
+pub fn table(set: &ResultSet) -> String {
+    let cells: Vec<Vec<String>> = set
+        .rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|v| match v {
+                    Value::Null => "NULL".to_owned(),
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect()
+        })
+        .collect();
+
+    let widths: Vec<usize> = set
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(i, col)| {
+            cells
+                .iter()
+                .map(|row| row[i].chars().count())
+                .chain([col.chars().count()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let border = widths
+        .iter()
+        .map(|w| "-".repeat(w + 2))
+        .fold("+".to_owned(), |line, dashes| line + &dashes + "+")
+        + "\n";
+    let line = |row: &[String]| {
+        row.iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!(" {cell:w$} "))
+            .fold("|".to_owned(), |line, cell| line + &cell + "|")
+            + "\n"
+    };
+
+    let mut out = border.clone() + &line(&set.columns) + &border;
+    for row in &cells {
+        out += &line(row);
+    }
+    if !cells.is_empty() {
+        out += &border;
+    }
+    let n = cells.len();
+    out + &format!("({n} row{})\n\n", if n == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(setup: &str, script: &str) -> Result<Vec<ResultSet>> {
+        let conn = open(setup, None)?;
+        let mut results = vec![];
+        execute(&conn, script, |set| results.push(set))?;
+        Ok(results)
+    }
+
+    #[test]
+    fn only_queries_have_results() {
+        let sets = results(
+            "CREATE TABLE t (id INTEGER, name TEXT); INSERT INTO t VALUES (1, 'a'), (2, NULL);",
+            "INSERT INTO t VALUES (3, 'c'); SELECT * FROM t ORDER BY id; SELECT count(*) AS n FROM t WHERE 0;",
+        )
+        .unwrap();
+
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].columns, ["id", "name"]);
+        assert_eq!(
+            sets[0].rows,
+            [
+                vec![json!(1), json!("a")],
+                vec![json!(2), Value::Null],
+                vec![json!(3), json!("c")]
+            ]
+        );
+        assert_eq!(sets[1].rows, [vec![json!(0)]]);
+    }
+
+    #[test]
+    fn errors_say_which_statement() {
+        let err = results("", "SELECT 1; SELECT * FROM nope;").unwrap_err();
+        assert!(format!("{err:#}").starts_with("Statement 2: "), "{err:#}");
+    }
+
+    #[test]
+    fn tables() {
+        let set = ResultSet {
+            columns: vec!["id".to_owned(), "name".to_owned()],
+            rows: vec![vec![json!(10), json!("ab")], vec![json!(2), Value::Null]],
+        };
+        assert_eq!(
+            table(&set),
+            "+----+------+\n\
+             | id | name |\n\
+             +----+------+\n\
+             | 10 | ab   |\n\
+             | 2  | NULL |\n\
+             +----+------+\n\
+             (2 rows)\n\n"
+        );
+    }
+
+    #[test]
+    fn unknown_fixture() {
+        let err = open("chinook", None).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("No fixture database called \"chinook\""));
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::{anyhow, Context, Result};
+use pyrod_service::serve;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::net::{TcpStream, UnixStream};
+use tokio_vsock::VsockStream;
+
+/// How pyrod connects back to the host
+/// pyrod always initiates the connection when it's ready, the host is listening
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// `vsock[[:cid]:port]`, what we use in a VM. The cid is the host's and the port 5000 if they're left out
+    Vsock { cid: u32, port: u32 },
+    /// `unix:/path/to/socket`
+    Unix(PathBuf),
+    /// `tcp:host:port`
+    Tcp(SocketAddr),
+}
+
+/// The host is always CID 2 from inside the VM
+const HOST_CID: u32 = 2;
+const DEFAULT_PORT: u32 = 5000;
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Vsock {
+            cid: HOST_CID,
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, rest) = s.split_once(':').unwrap_or((s, ""));
+        match kind {
+            "vsock" => {
+                let parts = rest
+                    .split(':')
+                    .filter(|p| !p.is_empty())
+                    .map(|p| p.parse().context(format!("Invalid vsock address {s:?}")))
+                    .collect::<Result<Vec<u32>>>()?;
+                match parts[..] {
+                    [] => Ok(Transport::default()),
+                    [port] => Ok(Transport::Vsock {
+                        cid: HOST_CID,
+                        port,
+                    }),
+                    [cid, port] => Ok(Transport::Vsock { cid, port }),
+                    _ => Err(anyhow!("Invalid vsock address {s:?}")),
+                }
+            }
+            "unix" if !rest.is_empty() => Ok(Transport::Unix(rest.into())),
+            "tcp" => Ok(Transport::Tcp(
+                rest.parse().context(format!("Invalid tcp address {s:?}"))?,
+            )),
+            _ => Err(anyhow!(
+                "Unknown transport {s:?}, expected vsock[[:cid]:port], unix:<path> or tcp:<addr>"
+            )),
+        }
+    }
+}
+
+impl Transport {
+    /// Connect back to the host and serve RPC calls until the host hangs up
+    pub async fn serve(&self) -> Result<()> {
+        match self {
+            Transport::Vsock { cid, port } => {
+                let stream = VsockStream::connect(*cid, *port).await?;
+                tracing::info!("Vsock connection opened on port {}", port);
+                serve(stream).await;
+            }
+            Transport::Unix(path) => {
+                let stream = UnixStream::connect(path)
+                    .await
+                    .context(format!("Failed to connect to unix socket {path:?}"))?;
+                tracing::info!("Unix socket connection opened on {:?}", path);
+                serve(stream).await;
+            }
+            Transport::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)
+                    .await
+                    .context(format!("Failed to connect to {addr}"))?;
+                tracing::info!("TCP connection opened to {}", addr);
+                serve(stream).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_transports() {
+        let vsock = |cid, port| Transport::Vsock { cid, port };
+        assert_eq!("vsock".parse::<Transport>().unwrap(), vsock(2, 5000));
+        assert_eq!("vsock:6000".parse::<Transport>().unwrap(), vsock(2, 6000));
+        assert_eq!("vsock:3:6000".parse::<Transport>().unwrap(), vsock(3, 6000));
+        assert_eq!(
+            "unix:/run/pyrod.sock".parse::<Transport>().unwrap(),
+            Transport::Unix("/run/pyrod.sock".into())
+        );
+        assert_eq!(
+            "tcp:127.0.0.1:5000".parse::<Transport>().unwrap(),
+            Transport::Tcp("127.0.0.1:5000".parse().unwrap())
+        );
+
+        assert!("vsock:1:2:3".parse::<Transport>().is_err());
+        assert!("vsock:port".parse::<Transport>().is_err());
+        assert!("unix".parse::<Transport>().is_err());
+        assert!("tcp:localhost".parse::<Transport>().is_err());
+        assert_eq!(
+            "http://host".parse::<Transport>().unwrap_err().to_string(),
+            "Unknown transport \"http://host\", expected vsock[[:cid]:port], unix:<path> or tcp:<addr>"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::JsonError;
+use crate::logs::AttemptLogs;
+use axum::{
+    extract::Path,
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response, Result},
+    routing::{get, post},
+    Json, Router,
+};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct RunLogsResponse {
+    run_id: Uuid,
+    /// Logs for each boot attempt, keyed by attempt number and then file name
+    attempts: BTreeMap<u32, AttemptLogs>,
+}
+
+#[tracing::instrument]
+async fn run_logs(Path(run_id): Path<Uuid>) -> Result<Json<RunLogsResponse>> {
+    //this is all blocking filesystem stuff
+    let attempts = tokio::task::spawn_blocking(move || crate::logs::read(run_id))
+        .await
+        .map_err(|e| JsonError::from(e).into_response())?
+        .map_err(|e| JsonError::from(e).into_response())?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(JsonError::from(format!("No logs for run {run_id}"))),
+            )
+                .into_response()
+        })?;
+
+    Ok(Json(RunLogsResponse { run_id, attempts }))
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct ReloadResponse {
+    max_vms: usize,
+}
+
+/// Re-read the config file, same as sending SIGHUP
+#[tracing::instrument(skip(vms))]
+async fn reload(State(vms): State<Arc<Semaphore>>) -> Result<Json<ReloadResponse>> {
+    let config = crate::reload::reload(&vms).map_err(|e| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(JsonError::from(format!("{e:#}"))),
+        )
+            .into_response()
+    })?;
+
+    Ok(Json(ReloadResponse {
+        max_vms: config.runner_config.max_vms,
+    }))
+}
+
+/// Only let requests through if they have the admin token as a bearer token
+async fn require_admin<B>(req: Request<B>, next: Next<B>) -> Response {
+    let Some(token) = &crate::config::get().admin_token else {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(JsonError::from("Admin API is disabled")),
+        )
+            .into_response();
+    };
+
+    let authorised = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        //hashed first so the length doesn't leak either, and compared in constant time
+        .is_some_and(|t| bool::from(Sha256::digest(t).ct_eq(&Sha256::digest(token))));
+
+    if !authorised {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(JsonError::from("Missing or invalid admin token")),
+        )
+            .into_response();
+    }
+
+    next.run(req).await
+}
+
+pub fn routes() -> Router<Arc<Semaphore>> {
+    Router::new()
+        .route("/runs/:id/logs", get(run_logs))
+        .route("/reload", post(reload))
+        .route_layer(middleware::from_fn(require_admin))
+}
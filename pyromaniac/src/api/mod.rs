@@ -1,36 +1,73 @@
-use std::{sync::Arc, time::Duration};
+mod admin;
 
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::config::{Config, LanguageConfig, LanguageOption};
+use crate::logs::RunLogs;
+use crate::results::{self, Begin, Stored};
 use crate::runner::run_code;
+use crate::sessions::{self, SessionError};
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::{IntoResponse, Result},
-    routing::post,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response, Result},
+    routing::{delete, get, post},
     Json, Router,
 };
+use pyrod_service::{is_relative_path, Submission};
 use tokio::sync::Semaphore;
+use uuid::Uuid;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 struct CodeRun {
     code: String,
     input: Option<String>,
-    lang: pyrod_service::Language,
+    /// Name or alias of the language, case doesn't matter
+    /// Can be followed by @version, eg python@3.12
+    lang: String,
+    /// Version of the language, if it isn't given in `lang`. Otherwise it's the language's default
+    version: Option<String>,
+    /// Choices about how the code is compiled and run, from what the language allows
+    #[serde(default)]
+    options: BTreeMap<String, serde_json::Value>,
+    /// A `requirements.txt`, `Cargo.toml` or the like, for languages that install dependencies from one
+    manifest: Option<String>,
+    /// Any other source files, by where they go relative to the language's workdir
+    #[serde(default)]
+    files: BTreeMap<PathBuf, String>,
+    /// Send back the result of the same request if it's been run before, rather than running it again
+    #[serde(default)]
+    cache: bool,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 struct CodeOutput {
+    run_id: Uuid,
     stdout: String,
     stderr: String,
+    /// Compiler warnings and the like, or its errors if it failed, left out if there weren't any
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    compile_output: String,
+    /// The code didn't compile, so `compile_output` has the errors and it wasn't run
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    compile_error: bool,
+    /// Structured results, only for languages that have them (eg each result set for SQL)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    results: Option<serde_json::Value>,
+    /// Whether this is the result of an earlier run of the same request
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
 }
 
-#[tracing::instrument(ret)]
+#[tracing::instrument(ret, skip(headers))]
 async fn run(
     State(semaphore): State<Arc<Semaphore>>,
+    headers: HeaderMap,
     Json(req): Json<CodeRun>,
-) -> Result<Json<CodeOutput>> {
+) -> Result<Response> {
     //there's two things done here to bound the number of VMs running
 
-    //Tasks are spawned with a timeout of 30 seconds.
+    //Tasks are spawned with a timeout covering every boot attempt plus the language's timeouts.
     //after that the task is dropped, therefore machine dropped and process killed
 
     //We also have a Semaphore. Tasks must acquire a permit from the semaphore
@@ -38,40 +75,332 @@ async fn run(
 
     //this does mean requests may take a while, so we need to set HTTP timeouts appropriately
 
+    //the run sticks with the config as it is now, even if it's reloaded halfway through
+    let config = crate::config::get();
+
+    let bad_request = |e| (StatusCode::BAD_REQUEST, Json(JsonError::from(e))).into_response();
+    let language = resolve(&config, &req.lang, req.version.as_deref()).map_err(bad_request)?;
+    let spec = language.with_options(&req.options).map_err(bad_request)?;
+    if req.manifest.is_some() && spec.manifest.is_none() {
+        let no_manifest = format!("{} doesn't take a manifest", spec.name);
+        return Err(bad_request(no_manifest).into());
+    }
+    if let Some(path) = req.files.keys().find(|path| !is_relative_path(path)) {
+        let bad_path = format!("Can't write a file to {path:?}, it has to be a relative path");
+        return Err(bad_request(bad_path).into());
+    }
+    let submission = Submission {
+        code: req.code,
+        files: req.files,
+        manifest: req.manifest,
+        input: req.input.unwrap_or("".to_string()),
+        return_artifact: false,
+    };
+
+    //the same request hashes the same, however its JSON was written
+    let request = results::key(language, &spec, &submission);
+
+    //a retry gets what the first request got, rather than running the code again
+    let idempotency = match headers.get("Idempotency-Key") {
+        Some(key) => {
+            let key = key
+                .to_str()
+                .map_err(|_| bad_request("Idempotency-Key isn't valid".to_owned()))?;
+            match results::begin(key, &request).await {
+                Begin::Run(pending) => Some(pending),
+                Begin::Replay(stored) => return Ok(stored.into_response()),
+                Begin::Running => {
+                    let running = "A request with this Idempotency-Key is still running";
+                    return Err((StatusCode::CONFLICT, Json(JsonError::from(running))).into());
+                }
+                Begin::Mismatch => {
+                    let mismatch = "This Idempotency-Key was already used for a different request";
+                    return Err((
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        Json(JsonError::from(mismatch)),
+                    )
+                        .into());
+                }
+            }
+        }
+        None => None,
+    };
+
+    if req.cache {
+        let cached = results::get(&request)
+            .await
+            .and_then(|body| serde_json::from_str(&body).ok());
+        crate::metrics::get().record_result_cache(cached.is_some());
+        if let Some(output) = cached {
+            let stored = Stored::ok(&CodeOutput {
+                cached: true,
+                ..output
+            });
+            if let Some(pending) = idempotency {
+                pending.finish(stored.clone()).await;
+            }
+            return Ok(stored.into_response());
+        }
+    }
+
     let _permit = semaphore
         .acquire_owned()
         .await
         .map_err(|e| JsonError::from(e).into_response())?;
 
-    let (stdout, stderr) = tokio::time::timeout(
-        Duration::from_secs(30),
-        run_code(req.lang, req.code, req.input.unwrap_or("".to_string())),
+    //every run gets an id, which is returned even if it fails so the logs can be found
+    let logs = RunLogs::new()
+        .await
+        .map_err(|e| JsonError::from(e).into_response())?;
+
+    let deadline = config.runner_config.boot_timeout * (config.runner_config.boot_retries + 1)
+        + language.compile_timeout
+        + language.run_timeout
+        + Duration::from_secs(10);
+
+    let result = tokio::time::timeout(
+        deadline,
+        run_code(&config, language, spec, submission, &logs),
     )
-    .await
-    .map_err(|e| JsonError::from(e).into_response())? // Result::flatten is nightly still
-    .map_err(|e| JsonError::from(e).into_response())?;
+    .await;
+
+    logs.finish();
+
+    let output = result
+        .map_err(|e| JsonError::from(e).for_run(logs.id)) // Result::flatten is nightly still
+        .and_then(|output| output.map_err(|e| JsonError::from(e).for_run(logs.id)));
+
+    let stored = match output {
+        Ok(output) => {
+            let stored = Stored::ok(&CodeOutput {
+                run_id: logs.id,
+                stdout: output.stdout,
+                stderr: output.stderr,
+                compile_output: output.compile_output,
+                compile_error: output.compile_error,
+                results: output.results,
+                cached: false,
+            });
+            if req.cache {
+                results::put(&request, &stored.body).await;
+            }
+            stored
+        }
+        Err(e) => Stored {
+            status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            body: serde_json::to_string(&e).unwrap_or_default(),
+        },
+    };
+    if let Some(pending) = idempotency {
+        pending.finish(stored.clone()).await;
+    }
+    Ok(stored.into_response())
+}
+
+impl Stored {
+    fn ok(output: &CodeOutput) -> Stored {
+        Stored {
+            status: StatusCode::OK.as_u16(),
+            body: serde_json::to_string(output).expect("Output can always be serialized"),
+        }
+    }
+}
+
+impl IntoResponse for Stored {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (
+            status,
+            [(header::CONTENT_TYPE, "application/json")],
+            self.body,
+        )
+            .into_response()
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct SessionOpen {
+    /// The same as for a run, but the language has to have a REPL
+    lang: String,
+    version: Option<String>,
+    #[serde(default)]
+    options: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct SessionInfo {
+    session_id: Uuid,
+    lang: String,
+    version: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Snippet {
+    code: String,
+}
 
-    Ok(Json(CodeOutput { stdout, stderr }))
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct SnippetOutput {
+    stdout: String,
+    stderr: String,
+}
+
+/// Start a REPL in a VM of its own, which is kept until it's closed or it's idle for too long
+#[tracing::instrument(ret)]
+async fn open_session(
+    State(semaphore): State<Arc<Semaphore>>,
+    Json(req): Json<SessionOpen>,
+) -> Result<(StatusCode, Json<SessionInfo>)> {
+    let config = crate::config::get();
+
+    let bad_request = |e| (StatusCode::BAD_REQUEST, Json(JsonError::from(e))).into_response();
+    let language = resolve(&config, &req.lang, req.version.as_deref()).map_err(bad_request)?;
+    let spec = language.with_options(&req.options).map_err(bad_request)?;
+    if spec.repl.is_none() {
+        return Err(bad_request(format!("{} doesn't have a REPL", spec.name)).into());
+    }
+    let lang = spec.name.clone();
+
+    let session_id = sessions::open(&config, language, spec, semaphore).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(SessionInfo {
+            session_id,
+            lang,
+            version: language.version.clone(),
+        }),
+    ))
 }
 
-pub fn app() -> Router {
+/// Run a snippet in a session, with everything the ones before it left behind
+#[tracing::instrument(ret, skip(req))]
+async fn eval(Path(id): Path<Uuid>, Json(req): Json<Snippet>) -> Result<Json<SnippetOutput>> {
+    let output = sessions::eval(id, req.code).await?;
+    Ok(Json(SnippetOutput {
+        stdout: output.stdout,
+        stderr: output.stderr,
+    }))
+}
+
+#[tracing::instrument(ret)]
+async fn close_session(Path(id): Path<Uuid>) -> Result<StatusCode> {
+    sessions::close(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+impl IntoResponse for SessionError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            SessionError::NotFound(_) => StatusCode::NOT_FOUND,
+            SessionError::Ended(..) => StatusCode::GONE,
+            SessionError::TooMany(_) | SessionError::NoVms => StatusCode::SERVICE_UNAVAILABLE,
+            SessionError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(JsonError::from(self))).into_response()
+    }
+}
+
+/// Find the language and version a request is asking for
+fn resolve<'a>(
+    config: &'a Config,
+    lang: &str,
+    version: Option<&str>,
+) -> Result<&'a LanguageConfig, String> {
+    let (name, version) = match (lang.split_once('@'), version) {
+        (Some((_, v)), Some(version)) if v != version => {
+            return Err(format!(
+                "lang asks for version {v:?}, but version is {version:?}"
+            ))
+        }
+        (Some((name, v)), _) => (name, Some(v)),
+        (None, version) => (lang, version),
+    };
+
+    let language = config
+        .language(name)
+        .ok_or_else(|| format!("Unknown language {name:?}"))?;
+    match version {
+        Some(v) => language.version(v).ok_or_else(|| {
+            format!(
+                "Unknown version {v:?} of {}, there's: {}",
+                language.spec.name,
+                language
+                    .versions
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }),
+        None => Ok(language),
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct LanguageInfo {
+    name: String,
+    aliases: Vec<String>,
+    /// What's run if a request doesn't ask for a version, if it's named
+    default_version: Option<String>,
+    versions: Vec<String>,
+    /// What requests can choose, and what they can choose from
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    options: BTreeMap<String, LanguageOption>,
+}
+
+/// Every language, and the versions of it that can be asked for
+async fn languages() -> Json<Vec<LanguageInfo>> {
+    let config = crate::config::get();
+    Json(
+        config
+            .languages
+            .values()
+            .map(|lang| LanguageInfo {
+                name: lang.spec.name.clone(),
+                aliases: lang.aliases.clone(),
+                default_version: lang.version.clone(),
+                versions: lang.versions.keys().cloned().collect(),
+                options: lang.options.clone(),
+            })
+            .collect(),
+    )
+}
+
+/// `vms` bounds the number of sandboxes running at once, and is resized when the config is reloaded
+pub fn app(vms: Arc<Semaphore>) -> Router {
     Router::new()
         .route("/api/run", post(run))
-        .with_state(Arc::new(Semaphore::new(
-            crate::config::get().runner_config.max_vms,
-        )))
+        .route("/api/languages", get(languages))
+        .route("/api/sessions", post(open_session))
+        .route("/api/sessions/:id", delete(close_session))
+        .route("/api/sessions/:id/eval", post(eval))
+        .route("/metrics", get(|| async { crate::metrics::get().render() }))
+        .nest("/api/admin", admin::routes())
+        .with_state(vms)
         .fallback(|| async { (StatusCode::NOT_FOUND, "Not Found\n") })
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 struct JsonError {
     error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_id: Option<Uuid>,
+}
+
+impl JsonError {
+    fn for_run(self, id: Uuid) -> Self {
+        Self {
+            run_id: Some(id),
+            ..self
+        }
+    }
 }
 
 impl<E: ToString> From<E> for JsonError {
     fn from(value: E) -> Self {
         Self {
             error: value.to_string(),
+            run_id: None,
         }
     }
 }
@@ -81,3 +410,37 @@ impl IntoResponse for JsonError {
         (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(self)).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_versions() {
+        let mut config = Config::for_tests();
+        let python = config.languages.get_mut("python").unwrap();
+        let mut py312 = python.clone();
+        py312.version = Some("3.12".to_owned());
+        python.versions.insert("3.12".to_owned(), py312);
+
+        let version = |lang, version| {
+            resolve(&config, lang, version).map(|lang| lang.version.clone().unwrap_or_default())
+        };
+        assert_eq!(version("python", None).unwrap(), "3.11");
+        assert_eq!(version("py@3.12", None).unwrap(), "3.12");
+        assert_eq!(version("Python", Some("3.12")).unwrap(), "3.12");
+        assert_eq!(version("py@3.12", Some("3.12")).unwrap(), "3.12");
+        assert_eq!(
+            version("py@3.12", Some("3.11")).unwrap_err(),
+            "lang asks for version \"3.12\", but version is \"3.11\""
+        );
+        assert_eq!(
+            version("python@2.7", None).unwrap_err(),
+            "Unknown version \"2.7\" of python, there's: 3.11, 3.12"
+        );
+        assert_eq!(
+            version("cobol@85", None).unwrap_err(),
+            "Unknown language \"cobol\""
+        );
+    }
+}
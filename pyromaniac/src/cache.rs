@@ -0,0 +1,237 @@
+//! Compiled code from earlier runs, so running the same code again can skip compiling it
+//! Entries are files in the cache directory named by the hash of everything that goes into
+//! compiling, and the least recently used are deleted once they add up to more than the limit.
+
+use crate::config::LanguageConfig;
+use anyhow::{Context, Result};
+use pyrod_service::{Artifact, Language, Submission};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// What compiling some code made, and what the compiler had to say about it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Entry {
+    pub artifact: Artifact,
+    pub compile_output: String,
+}
+
+/// Which entries there are and how recently each was used
+#[derive(Debug, Default)]
+pub struct Lru {
+    /// Size and last use of each entry
+    entries: HashMap<String, (u64, u64)>,
+    /// Entries by last use, oldest first
+    order: BTreeMap<u64, String>,
+    size: u64,
+    tick: u64,
+}
+
+impl Lru {
+    /// Mark an entry as just used, false if there isn't one
+    pub fn touch(&mut self, key: &str) -> bool {
+        self.tick += 1;
+        let Some((_, used)) = self.entries.get_mut(key) else {
+            return false;
+        };
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.to_owned());
+        true
+    }
+
+    pub fn insert(&mut self, key: String, size: u64) {
+        self.remove(&key);
+        self.tick += 1;
+        self.entries.insert(key.clone(), (size, self.tick));
+        self.order.insert(self.tick, key);
+        self.size += size;
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some((size, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+            self.size -= size;
+        }
+    }
+
+    /// Take out the least recently used entries until their sizes add up to no more than `max`,
+    /// returning what was taken out
+    pub fn evict(&mut self, max: u64) -> Vec<String> {
+        let mut evicted = vec![];
+        while self.size > max {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            self.remove(&key);
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+/// The index for the directory it was read from, which changes if the config is reloaded
+static INDEX: Mutex<Option<(PathBuf, Lru)>> = Mutex::new(None);
+
+/// Do something with the index for `dir`, reading it from the directory if it hasn't been yet
+fn with_index<T>(dir: &Path, f: impl FnOnce(&mut Lru) -> T) -> T {
+    let mut index = INDEX.lock().unwrap();
+    if !matches!(&*index, Some((path, _)) if path == dir) {
+        *index = Some((dir.to_owned(), read_index(dir)));
+    }
+    let (_, lru) = index.as_mut().expect("Index was just read");
+    f(lru)
+}
+
+/// Entries already on disk, in the order they were last used
+fn read_index(dir: &Path) -> Lru {
+    let mut files: Vec<(SystemTime, String, u64)> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            //anything else is half-written
+            let is_key = name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit());
+            if !meta.is_file() || !is_key {
+                return None;
+            }
+            Some((meta.modified().ok()?, name, meta.len()))
+        })
+        .collect();
+    files.sort();
+
+    let mut lru = Lru::default();
+    for (_, key, size) in files {
+        lru.insert(key, size);
+    }
+    lru
+}
+
+/// Whether runs in a language can use the cache at all
+pub fn enabled(spec: &Language) -> bool {
+    crate::config::get().cache.max_bytes > 0 && spec.compile.is_some() && !spec.artifacts.is_empty()
+}
+
+/// Hash of everything that could change what compiling makes
+/// That's the whole language definition with options filled in, the image it's compiled in and
+/// the code and any other files. Input isn't, it only matters when running
+pub fn key(lang: &LanguageConfig, spec: &Language, submission: &Submission) -> String {
+    //a rebuilt image could have a different compiler
+    let image = std::fs::metadata(&lang.rootfs)
+        .and_then(|meta| meta.modified())
+        .ok();
+    let hashed = (
+        spec,
+        &lang.version,
+        &lang.rootfs,
+        image,
+        &submission.code,
+        &submission.files,
+        &submission.manifest,
+    );
+    let bytes = bincode::serialize(&hashed).expect("Can always serialize the cache key");
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Look up compiled code, marking it as used
+/// Reading it is file I/O under the index lock, so it's done off the async workers
+pub async fn get(key: &str) -> Option<Entry> {
+    let key = key.to_owned();
+    tokio::task::spawn_blocking(move || read(&key))
+        .await
+        .expect("Reading the cache panicked")
+}
+
+fn read(key: &str) -> Option<Entry> {
+    let dir = &crate::config::get().cache.path;
+    if !with_index(dir, |lru| lru.touch(key)) {
+        return None;
+    }
+
+    let path = dir.join(key);
+    let entry = std::fs::read(&path)
+        .map_err(anyhow::Error::from)
+        .and_then(|bytes| Ok(bincode::deserialize(&bytes)?));
+    match entry {
+        Ok(entry) => {
+            //so the order survives a restart
+            if let Err(e) =
+                std::fs::File::open(&path).and_then(|f| f.set_modified(SystemTime::now()))
+            {
+                tracing::debug!("Failed to touch cache entry {key}: {e}");
+            }
+            Some(entry)
+        }
+        Err(e) => {
+            tracing::warn!("Failed to read cache entry {key}, dropping it: {e:#}");
+            with_index(dir, |lru| lru.remove(key));
+            let _ = std::fs::remove_file(&path);
+            None
+        }
+    }
+}
+
+/// Keep compiled code, evicting the least recently used entries to make room
+/// Off the async workers for the same reason as `get`
+pub async fn put(key: String, entry: Entry) -> Result<()> {
+    tokio::task::spawn_blocking(move || write(&key, &entry)).await?
+}
+
+fn write(key: &str, entry: &Entry) -> Result<()> {
+    let config = &crate::config::get().cache;
+    let dir = &config.path;
+    let bytes = bincode::serialize(entry)?;
+    if bytes.len() as u64 > config.max_bytes {
+        tracing::debug!(
+            "Not caching {} bytes, it's bigger than the cache",
+            bytes.len()
+        );
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(dir).context(format!("Failed to create cache dir {dir:?}"))?;
+    //written next to where it goes then renamed, so it's never read half-written
+    let tmp = dir.join(format!("{key}.tmp"));
+    std::fs::write(&tmp, &bytes).context(format!("Failed to write cache entry {tmp:?}"))?;
+    std::fs::rename(&tmp, dir.join(key))?;
+
+    let evicted = with_index(dir, |lru| {
+        lru.insert(key.to_owned(), bytes.len() as u64);
+        lru.evict(config.max_bytes)
+    });
+    for key in evicted {
+        tracing::debug!("Evicting cache entry {key}");
+        if let Err(e) = std::fs::remove_file(dir.join(&key)) {
+            tracing::warn!("Failed to delete cache entry {key}: {e}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::default();
+        lru.insert("a".to_owned(), 10);
+        lru.insert("b".to_owned(), 10);
+        lru.insert("c".to_owned(), 10);
+        assert!(lru.touch("a"));
+        assert!(!lru.touch("d"));
+
+        //replacing an entry doesn't count it twice
+        lru.insert("c".to_owned(), 5);
+        assert_eq!(lru.size, 25);
+
+        assert_eq!(lru.evict(20), ["b"]);
+        assert_eq!(lru.evict(4), ["a", "c"]);
+        assert_eq!(lru.size, 0);
+        assert!(lru.order.is_empty());
+    }
+}
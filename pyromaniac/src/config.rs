@@ -1,8 +1,11 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
 use dotenvy::dotenv;
-use std::sync::OnceLock;
+use pyrod_service::{Language, Repl};
+use std::sync::{Arc, OnceLock, RwLock};
 use std::time::Duration;
 
 #[derive(Debug)]
@@ -10,85 +13,1239 @@ pub struct Config {
     pub resource_path: PathBuf,
     pub port: u16,
     pub runner_config: RunnerConfig,
+    /// Every language we can run, keyed by name, with the global defaults already filled in
+    pub languages: BTreeMap<String, LanguageConfig>,
+    pub logs: LogConfig,
+    pub cache: CacheConfig,
+    pub results: ResultsConfig,
+    pub sessions: SessionsConfig,
+    /// Bearer token for the admin API, which is disabled if this isn't set
+    pub admin_token: Option<String>,
+}
+
+/// Where to keep the console and firecracker logs from each run, and how many
+#[derive(Debug)]
+pub struct LogConfig {
+    pub path: PathBuf,
+    /// Logs from the oldest runs are deleted once there are more than this many
+    pub max_runs: usize,
+    /// Files bigger than this are cut down to their last `max_file_bytes`
+    pub max_file_bytes: u64,
+}
+
+/// Where to keep compiled code, so the same code isn't compiled again
+#[derive(Debug)]
+pub struct CacheConfig {
+    pub path: PathBuf,
+    /// The least recently used entries are deleted once they add up to more than this,
+    /// and nothing's cached at all if it's 0
+    pub max_bytes: u64,
+}
+
+/// Responses kept for requests that ask to reuse them, and for retries with the same idempotency key
+#[derive(Debug)]
+pub struct ResultsConfig {
+    /// SQLite database to keep them in too, otherwise they're only in memory
+    pub path: Option<PathBuf>,
+    /// The least recently used results are dropped once there are more than this many
+    pub max_entries: usize,
+    /// How long a response is kept for an idempotency key after it's sent
+    pub idempotency_ttl: Duration,
+}
+
+/// REPL sessions, which each keep a VM running between requests
+#[derive(Debug)]
+pub struct SessionsConfig {
+    /// How many can be open at once, each of which takes one of `max_vms`
+    pub max_sessions: usize,
+    /// Sessions nothing's been run in for this long are closed
+    pub idle_timeout: Duration,
 }
 
 #[derive(Debug)]
 pub struct RunnerConfig {
+    pub max_vms: usize,
+    /// How long to wait for pyrod to connect back after spawning the VM
+    pub boot_timeout: Duration,
+    /// How many times to retry booting a VM that started but failed to come up
+    pub boot_retries: u32,
+    pub uid: Option<u16>,
+    pub gid: Option<u16>,
+    pub jailer: JailerConfig,
+    pub backend: Backend,
+    pub local: LocalConfig,
+}
+
+/// How sandboxes for a language are set up, and what limits code in them runs under
+#[derive(Debug, Clone)]
+pub struct LanguageConfig {
+    /// What pyrod needs to know to compile and run code in the language
+    pub spec: Language,
+    /// Other names the language can be asked for by
+    pub aliases: Vec<String>,
     pub cpus: u32,
+    /// Guest memory in MiB
     pub memory: u32,
-    pub max_vms: usize,
     pub compile_timeout: Duration,
     pub run_timeout: Duration,
-    pub uid: Option<u16>,
-    pub gid: Option<u16>,
+    /// stdout and stderr are each cut off after this many bytes
+    pub max_output: usize,
+    /// ext4 image for firecracker, tarball for the local backend
+    pub rootfs: PathBuf,
+    /// Guest kernel, firecracker only
+    pub kernel: PathBuf,
+    /// Kernel command line, firecracker only
+    pub boot_args: String,
+    /// Which version of the language this is, if it's been given one
+    pub version: Option<String>,
+    /// Every named version of the language, including this one.
+    /// Only the language's default version has these
+    pub versions: BTreeMap<String, LanguageConfig>,
+    /// What requests can choose, by the var each one fills in
+    pub options: BTreeMap<String, LanguageOption>,
+}
+
+/// Something a request can choose about how its code is compiled or run, which fills in the var
+/// with the same name. Only values on the list are allowed
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageOption {
+    pub values: Vec<String>,
+    /// Used when a request doesn't choose. Options with multiple values are empty by default
+    #[serde(default)]
+    pub default: Option<String>,
+    /// Whether a request can choose any number of the values, which are joined with spaces
+    #[serde(default)]
+    pub multiple: bool,
 }
 
-static CONFIG: OnceLock<Config> = OnceLock::new();
+/// Vars that are filled in for every language, so can't be options
+const RESERVED_VARS: [&str; 6] = ["source", "workdir", "cpus", "memory", "heap", "version"];
 
-impl Config {
-    fn init_from_env() -> Result<Config> {
-        //load dotenv file if it exists
-        match dotenv() {
-            Err(_) => tracing::info!("No .env file found, nothing to load"),
-            Ok(_) => tracing::info!("Loaded config from .env file"),
+impl LanguageConfig {
+    /// A particular version, or None if it isn't installed
+    pub fn version(&self, version: &str) -> Option<&LanguageConfig> {
+        match &self.version {
+            Some(v) if v == version => Some(self),
+            _ => self.versions.get(version),
+        }
+    }
+
+    /// What to send pyrod, with a request's choices of options filled in
+    /// Strings, numbers and bools are all fine, and lists for options that can have multiple values
+    pub fn with_options(
+        &self,
+        options: &BTreeMap<String, serde_json::Value>,
+    ) -> Result<Language, String> {
+        let name = &self.spec.name;
+        let mut spec = self.spec.clone();
+        for (option, value) in options {
+            let allowed = self.options.get(option).ok_or_else(|| {
+                format!(
+                    "{name} doesn't have an option {option:?}, there's: {}",
+                    self.options.keys().cloned().collect::<Vec<_>>().join(", ")
+                )
+            })?;
+
+            let values = match value {
+                serde_json::Value::Array(values) if allowed.multiple => values.iter().collect(),
+                value => vec![value],
+            };
+            let values = values
+                .into_iter()
+                .map(|value| {
+                    let value = match value {
+                        serde_json::Value::String(s) => s.clone(),
+                        serde_json::Value::Bool(_) | serde_json::Value::Number(_) => {
+                            value.to_string()
+                        }
+                        _ => return Err(format!("Option {option:?} can't be {value}")),
+                    };
+                    if allowed.values.contains(&value) {
+                        Ok(value)
+                    } else {
+                        Err(format!(
+                            "Option {option:?} can't be {value:?}, it can be: {}",
+                            allowed.values.join(", ")
+                        ))
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            spec.vars.insert(option.clone(), values.join(" "));
+        }
+        Ok(spec)
+    }
+
+    pub fn limits(&self) -> pyrod_service::Limits {
+        pyrod_service::Limits {
+            compile_timeout: self.compile_timeout,
+            run_timeout: self.run_timeout,
+            max_output: self.max_output,
         }
+    }
+}
+
+/// Which kind of sandbox to run code in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Firecracker microVMs, needs KVM
+    Firecracker,
+    /// Host processes in linux namespaces, for development only
+    Local,
+}
+
+/// Options for the namespace-based local backend
+#[derive(Debug)]
+pub struct LocalConfig {
+    /// Size of the tmpfs the image is unpacked into
+    pub tmpfs_size_mib: u32,
+}
+
+/// Options passed to jailer when running in release mode
+/// See https://github.com/firecracker-microvm/firecracker/blob/main/docs/jailer.md
+#[derive(Debug)]
+pub struct JailerConfig {
+    /// Limit firecracker to as much CPU time as the VM has vCPUs, every `cpu_period_us` (`cpu.max`)
+    pub limit_cpu: bool,
+    pub cpu_period_us: u64,
+    /// Headroom over guest memory for the firecracker process itself (`memory.max`)
+    pub memory_overhead_mib: Option<u32>,
+    /// Max size in bytes of files the Firecracker process can create
+    pub fsize: Option<u64>,
+    /// Max number of file descriptors the Firecracker process can open
+    pub no_file: Option<u64>,
+    /// Path to a network namespace to join, eg /var/run/netns/pyromaniac
+    pub netns: Option<PathBuf>,
+    pub daemonize: bool,
+    pub new_pid_ns: bool,
+}
+
+/// Definitions for the languages we support out of the box, which the config file can override
+const BUILTIN_LANGUAGES: &str = include_str!("languages.toml");
+
+const DEFAULT_BOOT_ARGS: &str =
+    "init=/bin/pyrod console=ttyS0 reboot=k panic=1 pci=off random.trust_cpu=on";
+
+/// Used if no config file is given on the command line, but only if it exists
+const DEFAULT_CONFIG_PATH: &str = "pyromaniac.toml";
+
+/// How much of the guest's memory (MiB) a garbage collected runtime can have for its heap,
+/// leaving the rest for the kernel, pyrod and the runtime's own overhead so the guest doesn't OOM kill it
+fn heap_mib(memory: u32) -> u32 {
+    (memory.saturating_sub(256) * 3 / 4).max(64)
+}
+
+/// Command line flags, each of which can also be set from the environment (or .env)
+/// Anything set here overrides the config file
+#[derive(Debug, Clone, Parser)]
+#[command(about = "Run untrusted code in microVMs over HTTP")]
+struct Args {
+    /// TOML config file [default: ./pyromaniac.toml if it exists]
+    #[arg(long, short, env = "CONFIG_PATH")]
+    config: Option<PathBuf>,
+    /// Directory containing firecracker, jailer, the kernel and rootfs images
+    #[arg(long, env = "RESOURCE_PATH")]
+    resource_path: Option<PathBuf>,
+    #[arg(long, env = "PORT")]
+    port: Option<u16>,
+    #[arg(long, env = "BACKEND")]
+    backend: Option<Backend>,
+    /// Max number of sandboxes running at once
+    #[arg(long, env = "MAX_VMS")]
+    max_vms: Option<usize>,
+    /// User to run firecracker as under jailer
+    #[arg(long, env = "UID")]
+    uid: Option<u16>,
+    /// Group to run firecracker as under jailer
+    #[arg(long, env = "GID")]
+    gid: Option<u16>,
+    /// Network namespace for jailer to put VMs in
+    #[arg(long, env = "NETNS")]
+    netns: Option<PathBuf>,
+    #[arg(long, env = "LOG_PATH")]
+    log_path: Option<PathBuf>,
+    /// Directory for compiled code
+    #[arg(long, env = "CACHE_PATH")]
+    cache_path: Option<PathBuf>,
+    /// SQLite database for results and idempotency keys [default: in memory only]
+    #[arg(long, env = "RESULTS_PATH")]
+    results_path: Option<PathBuf>,
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+}
 
-        let resource_path = dotenvy::var("RESOURCE_PATH")
-            .map_err(Into::<anyhow::Error>::into) //error trait bullshit
-            .unwrap_or_else(|_| {
+//everything in the file is optional, defaults are filled in when we build the real config
+//durations are in seconds
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    global: GlobalFile,
+    logs: LogsFile,
+    cache: CacheFile,
+    results: ResultsFile,
+    sessions: SessionsFile,
+    jailer: JailerFile,
+    local: LocalFile,
+    languages: HashMap<String, LanguageFile>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GlobalFile {
+    resource_path: Option<PathBuf>,
+    port: Option<u16>,
+    backend: Option<Backend>,
+    max_vms: Option<usize>,
+    boot_timeout: Option<f64>,
+    boot_retries: Option<u32>,
+    uid: Option<u16>,
+    gid: Option<u16>,
+    admin_token: Option<String>,
+    //defaults for every language
+    cpus: Option<u32>,
+    memory: Option<u32>,
+    compile_timeout: Option<f64>,
+    run_timeout: Option<f64>,
+    max_output: Option<usize>,
+    kernel: Option<PathBuf>,
+    boot_args: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LanguageFile {
+    aliases: Option<Vec<String>>,
+    workdir: Option<PathBuf>,
+    source: Option<PathBuf>,
+    //an empty list means no compile step
+    compile: Option<Vec<String>>,
+    run: Option<Vec<String>>,
+    env: BTreeMap<String, String>,
+    vars: BTreeMap<String, String>,
+    results: Option<PathBuf>,
+    manifest: Option<PathBuf>,
+    //only run when a request sends a manifest, an empty list means there's nothing to install
+    install: Option<Vec<String>>,
+    artifacts: Option<Vec<PathBuf>>,
+    repl: Option<Repl>,
+    cpus: Option<u32>,
+    memory: Option<u32>,
+    compile_timeout: Option<f64>,
+    run_timeout: Option<f64>,
+    max_output: Option<usize>,
+    //which scripts/images build the rootfs comes from, if it's shared with other languages
+    image: Option<String>,
+    rootfs: Option<PathBuf>,
+    kernel: Option<PathBuf>,
+    boot_args: Option<String>,
+    //used when a request doesn't ask for a version, rather than the unversioned image
+    default_version: Option<String>,
+    //anything that's different about particular versions, on top of the rest of this
+    versions: HashMap<String, LanguageFile>,
+    options: BTreeMap<String, LanguageOption>,
+}
+
+impl LanguageFile {
+    /// Anything set in `over` wins, environment variables, template vars and versions are merged
+    fn merge(mut self, over: LanguageFile) -> LanguageFile {
+        self.env.extend(over.env);
+        self.vars.extend(over.vars);
+        self.options.extend(over.options);
+        for (version, over) in over.versions {
+            let version_file = self.versions.remove(&version).unwrap_or_default();
+            self.versions.insert(version, version_file.merge(over));
+        }
+        LanguageFile {
+            aliases: over.aliases.or(self.aliases),
+            workdir: over.workdir.or(self.workdir),
+            source: over.source.or(self.source),
+            compile: over.compile.or(self.compile),
+            run: over.run.or(self.run),
+            env: self.env,
+            vars: self.vars,
+            results: over.results.or(self.results),
+            manifest: over.manifest.or(self.manifest),
+            install: over.install.or(self.install),
+            artifacts: over.artifacts.or(self.artifacts),
+            repl: over.repl.or(self.repl),
+            cpus: over.cpus.or(self.cpus),
+            memory: over.memory.or(self.memory),
+            compile_timeout: over.compile_timeout.or(self.compile_timeout),
+            run_timeout: over.run_timeout.or(self.run_timeout),
+            max_output: over.max_output.or(self.max_output),
+            image: over.image.or(self.image),
+            rootfs: over.rootfs.or(self.rootfs),
+            kernel: over.kernel.or(self.kernel),
+            boot_args: over.boot_args.or(self.boot_args),
+            default_version: over.default_version.or(self.default_version),
+            versions: self.versions,
+            options: self.options,
+        }
+    }
+}
+
+/// Versions of an image there are rootfs images for, from their `rootfs-<image>-<version>` names
+fn installed_versions(resource_path: &Path, image: &str, backend: Backend) -> Vec<String> {
+    let prefix = format!("rootfs-{image}-");
+    let ext = match backend {
+        Backend::Firecracker => ".ext4",
+        Backend::Local => ".tar",
+    };
+    let Ok(dir) = std::fs::read_dir(resource_path) else {
+        return vec![];
+    };
+    dir.filter_map(|entry| {
+        let name = entry.ok()?.file_name().into_string().ok()?;
+        let version = name.strip_prefix(&prefix)?.strip_suffix(ext)?;
+        (!version.is_empty()).then(|| version.to_owned())
+    })
+    .collect()
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogsFile {
+    path: Option<PathBuf>,
+    max_runs: Option<usize>,
+    max_file_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CacheFile {
+    path: Option<PathBuf>,
+    max_size_mib: Option<u64>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ResultsFile {
+    path: Option<PathBuf>,
+    max_entries: Option<usize>,
+    idempotency_ttl: Option<f64>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SessionsFile {
+    max_sessions: Option<usize>,
+    idle_timeout: Option<f64>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct JailerFile {
+    limit_cpu: Option<bool>,
+    cpu_period_us: Option<u64>,
+    memory_overhead_mib: Option<u32>,
+    fsize: Option<u64>,
+    no_file: Option<u64>,
+    netns: Option<PathBuf>,
+    daemonize: Option<bool>,
+    new_pid_ns: Option<bool>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LocalFile {
+    tmpfs_size_mib: Option<u32>,
+}
+
+//swapped out wholesale on reload, anything holding the old one keeps using it
+static CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+
+//flags and environment are only read once at startup, reloading just re-reads the file
+static ARGS: OnceLock<Args> = OnceLock::new();
+
+impl Config {
+    fn load(args: Args) -> Result<Config> {
+        let file = match &args.config {
+            Some(path) => read_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                read_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => {
+                tracing::info!("No config file found, using defaults");
+                File::default()
+            }
+        };
+
+        let config = Config::build(args, file)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Merge flags and the file over the defaults
+    fn build(args: Args, file: File) -> Result<Config> {
+        let global = file.global;
+
+        let resource_path = args
+            .resource_path
+            .or(global.resource_path)
+            .unwrap_or_else(|| {
                 tracing::warn!("No resource path provided defaulting to ./resources");
-                "./resources".to_owned()
-            })
-            .into();
-
-        //port defaults to 3000 if not provided
-        let port = dotenvy::var("PORT")
-            .map_err(Into::<anyhow::Error>::into) //error trait bullshit
-            .and_then(|p| p.parse().map_err(Into::into))
-            .unwrap_or_else(|_| {
-                tracing::warn!("No port provided, defaulting to 3000");
-                3000
+                "./resources".into()
             });
 
-        let max_vms = std::thread::available_parallelism()
-            .expect("Failed to determine number of CPUs")
-            .get()
-            * 2;
+        let port = args.port.or(global.port).unwrap_or_else(|| {
+            tracing::warn!("No port provided, defaulting to 3000");
+            3000
+        });
 
-        let uid = dotenvy::var("UID")
-            .map_err(Into::<anyhow::Error>::into) //error trait bullshit
-            .and_then(|p| p.parse::<u16>().map_err(Into::into))
-            .ok();
+        let backend = args.backend.or(global.backend).unwrap_or_else(|| {
+            tracing::info!("No backend provided, defaulting to firecracker");
+            Backend::Firecracker
+        });
 
-        let gid = dotenvy::var("GID")
-            .map_err(Into::<anyhow::Error>::into) //error trait bullshit
-            .and_then(|p| p.parse::<u16>().map_err(Into::into))
-            .ok();
+        let max_vms = match args.max_vms.or(global.max_vms) {
+            Some(max_vms) => max_vms,
+            None => {
+                std::thread::available_parallelism()
+                    .context("Failed to determine number of CPUs, set global.max_vms instead")?
+                    .get()
+                    * 2
+            }
+        };
 
-        //if release mode and we don't have uid/gid
-        if !cfg!(debug_assertions) && (gid.is_none() || uid.is_none()) {
-            panic!("No uid/gid provided but running in release mode, will be unable to start firecracker")
+        let admin_token = args.admin_token.or(global.admin_token);
+        if admin_token.is_none() {
+            tracing::warn!("No admin token provided, admin API is disabled");
         }
 
-        let c = Ok(Config {
+        let log_path = args.log_path.or(file.logs.path).unwrap_or_else(|| {
+            tracing::info!("No log path provided, defaulting to ./logs");
+            "./logs".into()
+        });
+
+        let cache_path = args.cache_path.or(file.cache.path).unwrap_or_else(|| {
+            tracing::info!("No cache path provided, defaulting to ./cache");
+            "./cache".into()
+        });
+
+        //paths in the file are relative to the resource directory
+        let resource = |p: PathBuf| resource_path.join(p);
+
+        let mut languages: HashMap<String, LanguageFile> = toml::from_str(BUILTIN_LANGUAGES)
+            .context("Built-in language definitions are invalid")?;
+        for (name, lang) in file.languages {
+            let builtin = languages.remove(&name).unwrap_or_default();
+            languages.insert(name, builtin.merge(lang));
+        }
+
+        //`key` is where the language (or version of it) is in the file, for errors
+        let build_language = |name: &str,
+                              key: &str,
+                              lang: LanguageFile,
+                              version: Option<&str>|
+         -> Result<LanguageConfig> {
+            let required = |field: &str| {
+                anyhow!("languages.{key}.{field}: must be set for languages that aren't built in")
+            };
+            let secs = |field: &str, value: Option<f64>, default: u64| {
+                value
+                    .map(|s| {
+                        Duration::try_from_secs_f64(s).map_err(|_| {
+                            anyhow!("languages.{key}.{field}: {s} is not a valid number of seconds")
+                        })
+                    })
+                    .unwrap_or(Ok(Duration::from_secs(default)))
+            };
+            let image = match version {
+                Some(version) => format!("{}-{version}", lang.image.as_deref().unwrap_or(name)),
+                None => lang.image.clone().unwrap_or(name.to_owned()),
+            };
+            let rootfs = match backend {
+                Backend::Firecracker => format!("rootfs-{image}.ext4"),
+                Backend::Local => format!("rootfs-{image}.tar"),
+            };
+
+            let cpus = lang.cpus.or(global.cpus).unwrap_or(1);
+            let memory = lang.memory.or(global.memory).unwrap_or(1024);
+
+            //commands can size themselves to the VM, unless the language says otherwise
+            let mut vars = lang.vars;
+            for (var, value) in [
+                ("cpus", cpus),
+                ("memory", memory),
+                ("heap", heap_mib(memory)),
+            ] {
+                vars.entry(var.to_owned()).or_insert(value.to_string());
+            }
+            //options are vars too, which requests can change
+            for (option, conf) in &lang.options {
+                vars.insert(option.clone(), conf.default.clone().unwrap_or_default());
+            }
+            //the unversioned image can say which version it is with a var
+            if let Some(version) = version {
+                vars.insert("version".to_owned(), version.to_owned());
+            }
+            let version = vars.get("version").cloned();
+
+            let spec = Language {
+                name: name.to_owned(),
+                workdir: lang.workdir.ok_or_else(|| required("workdir"))?,
+                source: lang.source.ok_or_else(|| required("source"))?,
+                compile: lang.compile.filter(|c| !c.is_empty()),
+                run: lang.run.ok_or_else(|| required("run"))?,
+                env: lang.env,
+                vars,
+                results: lang.results,
+                manifest: lang.manifest,
+                install: lang.install.filter(|i| !i.is_empty()),
+                artifacts: lang.artifacts.unwrap_or_default(),
+                repl: lang.repl,
+            };
+
+            Ok(LanguageConfig {
+                aliases: lang.aliases.unwrap_or_default(),
+                cpus,
+                memory,
+                compile_timeout: secs(
+                    "compile_timeout",
+                    lang.compile_timeout.or(global.compile_timeout),
+                    20,
+                )?,
+                run_timeout: secs("run_timeout", lang.run_timeout.or(global.run_timeout), 15)?,
+                max_output: lang.max_output.or(global.max_output).unwrap_or(1024 * 1024),
+                rootfs: resource(lang.rootfs.unwrap_or(rootfs.into())),
+                kernel: resource(
+                    lang.kernel
+                        .or(global.kernel.clone())
+                        .unwrap_or("kernel.bin".into()),
+                ),
+                boot_args: lang
+                    .boot_args
+                    .or(global.boot_args.clone())
+                    .unwrap_or(DEFAULT_BOOT_ARGS.to_owned()),
+                spec,
+                version,
+                versions: BTreeMap::new(),
+                options: lang.options,
+            })
+        };
+
+        let languages = languages
+            .into_iter()
+            .map(|(name, mut lang)| -> Result<_> {
+                //every installed image is a version, as well as any in the file
+                let image = lang.image.as_deref().unwrap_or(&name);
+                let mut version_files = std::mem::take(&mut lang.versions);
+                for version in installed_versions(&resource_path, image, backend) {
+                    version_files.entry(version).or_default();
+                }
+                let default_version = lang.default_version.take();
+
+                let mut versions = BTreeMap::new();
+                for (version, over) in version_files {
+                    let key = format!("{name}.versions.{version}");
+                    if !over.versions.is_empty() || over.default_version.is_some() {
+                        bail!("languages.{key}: versions can't have their own versions");
+                    }
+                    let conf = build_language(&name, &key, lang.clone().merge(over), Some(&version))?;
+                    versions.insert(version, conf);
+                }
+
+                //the unversioned image can be asked for by its version too, if it has one
+                let unversioned = build_language(&name, &name, lang, None)?;
+                if let Some(version) = &unversioned.version {
+                    versions
+                        .entry(version.clone())
+                        .or_insert(unversioned.clone());
+                }
+
+                let mut config = match default_version {
+                    Some(default) => match versions.get(&default) {
+                        Some(conf) => conf.clone(),
+                        None => bail!(
+                            "languages.{name}.default_version: {default:?} isn't installed, there's: {}",
+                            versions.keys().cloned().collect::<Vec<_>>().join(", ")
+                        ),
+                    },
+                    None => unversioned,
+                };
+                config.versions = versions;
+                Ok((name, config))
+            })
+            .collect::<Result<_>>()?;
+
+        let boot_timeout = match global.boot_timeout {
+            Some(s) => Duration::try_from_secs_f64(s).map_err(|_| {
+                anyhow!("global.boot_timeout: {s} is not a valid number of seconds")
+            })?,
+            None => Duration::from_secs(5),
+        };
+
+        let idempotency_ttl = match file.results.idempotency_ttl {
+            Some(s) => Duration::try_from_secs_f64(s).map_err(|_| {
+                anyhow!("results.idempotency_ttl: {s} is not a valid number of seconds")
+            })?,
+            None => Duration::from_secs(24 * 60 * 60),
+        };
+
+        let idle_timeout = match file.sessions.idle_timeout {
+            Some(s) => Duration::try_from_secs_f64(s).map_err(|_| {
+                anyhow!("sessions.idle_timeout: {s} is not a valid number of seconds")
+            })?,
+            None => Duration::from_secs(5 * 60),
+        };
+
+        let jailer = file.jailer;
+
+        Ok(Config {
             resource_path,
             port,
             runner_config: RunnerConfig {
-                cpus: 1,
-                memory: 1024,
                 max_vms,
-                compile_timeout: Duration::from_secs(20),
-                run_timeout: Duration::from_secs(15),
-                uid,
-                gid,
+                boot_timeout,
+                boot_retries: global.boot_retries.unwrap_or(2),
+                uid: args.uid.or(global.uid),
+                gid: args.gid.or(global.gid),
+                jailer: JailerConfig {
+                    //don't let the VMM use more than the vCPUs it was given
+                    limit_cpu: jailer.limit_cpu.unwrap_or(true),
+                    cpu_period_us: jailer.cpu_period_us.unwrap_or(100_000),
+                    //leave some headroom over guest memory for firecracker itself
+                    memory_overhead_mib: jailer.memory_overhead_mib.or(Some(128)),
+                    fsize: jailer.fsize,
+                    no_file: jailer.no_file.or(Some(2048)),
+                    netns: args.netns.or(jailer.netns),
+                    //daemonizing sends the VM's console to /dev/null, and we want to keep it
+                    daemonize: jailer.daemonize.unwrap_or(false),
+                    new_pid_ns: jailer.new_pid_ns.unwrap_or(true),
+                },
+                backend,
+                local: LocalConfig {
+                    tmpfs_size_mib: file.local.tmpfs_size_mib.unwrap_or(2048),
+                },
             },
+            languages,
+            logs: LogConfig {
+                path: log_path,
+                max_runs: file.logs.max_runs.unwrap_or(1000),
+                max_file_bytes: file.logs.max_file_bytes.unwrap_or(1024 * 1024),
+            },
+            cache: CacheConfig {
+                path: cache_path,
+                max_bytes: file.cache.max_size_mib.unwrap_or(1024) * 1024 * 1024,
+            },
+            results: ResultsConfig {
+                path: args.results_path.or(file.results.path),
+                max_entries: file.results.max_entries.unwrap_or(10_000),
+                idempotency_ttl,
+            },
+            sessions: SessionsConfig {
+                //leave some for plain runs
+                max_sessions: file.sessions.max_sessions.unwrap_or(max_vms / 2),
+                idle_timeout,
+            },
+            admin_token,
+        })
+    }
+
+    /// Check for anything that would stop us running code, reporting everything wrong at once
+    fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+        let runner = &self.runner_config;
+
+        if !self.resource_path.is_dir() {
+            errors.push(format!(
+                "global.resource_path: {:?} is not a directory",
+                self.resource_path
+            ));
+        }
+
+        if runner.max_vms == 0 {
+            errors.push("global.max_vms: must be at least 1".to_owned());
+        }
+
+        if runner.boot_timeout.is_zero() {
+            errors.push("global.boot_timeout: must be more than 0".to_owned());
+        }
+
+        //if release mode and we don't have uid/gid
+        if !cfg!(debug_assertions)
+            && runner.backend == Backend::Firecracker
+            && (runner.uid.is_none() || runner.gid.is_none())
+        {
+            errors.push(
+                "global.uid/global.gid: both are required to run firecracker under jailer in release mode".to_owned(),
+            );
+        }
+
+        if runner.jailer.cpu_period_us == 0 {
+            errors.push("jailer.cpu_period_us: must be more than 0".to_owned());
+        }
+
+        //every name has to pick out exactly one language
+        let mut names = HashMap::new();
+        for (lang, conf) in &self.languages {
+            for name in std::iter::once(lang).chain(&conf.aliases) {
+                if let Some(other) = names.insert(name.to_lowercase(), lang) {
+                    errors.push(format!(
+                        "languages.{lang}: {name:?} is already used by languages.{other}"
+                    ));
+                }
+            }
+        }
+
+        //each version is checked too, but the default only once
+        let all = self.languages.iter().flat_map(|(name, conf)| {
+            let versions = conf
+                .versions
+                .iter()
+                .filter(|(version, _)| conf.version.as_ref() != Some(version))
+                .map(move |(version, conf)| (format!("{name}.versions.{version}"), conf));
+            std::iter::once((name.clone(), conf)).chain(versions)
         });
+        for (lang, conf) in all {
+            let mut error = |msg: String| errors.push(format!("languages.{lang}.{msg}"));
+
+            if !conf.spec.workdir.is_absolute() {
+                error(format!("workdir: {:?} must be absolute", conf.spec.workdir));
+            }
+            if conf.spec.run.is_empty() {
+                error("run: must have at least a program to run".to_owned());
+            }
+
+            //firecracker supports at most 32 vCPUs
+            if !(1..=32).contains(&conf.cpus) {
+                error(format!("cpus: must be between 1 and 32, got {}", conf.cpus));
+            }
+            if conf.memory < 128 {
+                error(format!(
+                    "memory: must be at least 128 MiB, got {}",
+                    conf.memory
+                ));
+            }
+            if conf.compile_timeout.is_zero() {
+                error("compile_timeout: must be more than 0".to_owned());
+            }
+            if conf.run_timeout.is_zero() {
+                error("run_timeout: must be more than 0".to_owned());
+            }
+            if conf.max_output == 0 {
+                error("max_output: must be more than 0".to_owned());
+            }
+            for (option, allowed) in &conf.options {
+                if RESERVED_VARS.contains(&option.as_str()) {
+                    error(format!(
+                        "options.{option}: {option} is always filled in, so can't be an option"
+                    ));
+                }
+                if allowed.values.is_empty() {
+                    error(format!(
+                        "options.{option}.values: must have at least one value"
+                    ));
+                }
+                match (&allowed.default, allowed.multiple) {
+                    (Some(_), true) => error(format!(
+                        "options.{option}.default: can't be set for options with multiple values"
+                    )),
+                    (Some(default), false) if !allowed.values.contains(default) => error(format!(
+                        "options.{option}.default: {default:?} isn't one of the values"
+                    )),
+                    (None, false) => error(format!(
+                        "options.{option}.default: must be set for options with one value"
+                    )),
+                    _ => {}
+                }
+            }
+
+            //not fatal, the server is still useful for the languages that are there
+            if !conf.rootfs.is_file() {
+                tracing::warn!("No rootfs for {lang} at {:?}, runs will fail", conf.rootfs);
+            }
+            if runner.backend == Backend::Firecracker && !conf.kernel.is_file() {
+                tracing::warn!("No kernel for {lang} at {:?}, runs will fail", conf.kernel);
+            }
+        }
 
-        tracing::info!("Loaded config from environment!");
+        if !errors.is_empty() {
+            bail!("Invalid config:\n  {}", errors.join("\n  "));
+        }
+        Ok(())
+    }
+
+    /// The built-in languages and defaults, with nothing on disk
+    #[cfg(test)]
+    pub fn for_tests() -> Config {
+        let args = Args::parse_from(["pyromaniac", "--resource-path", "/res"]);
+        Config::build(args, File::default()).unwrap()
+    }
+
+    /// Look up a language by its name or any of its aliases, ignoring case
+    pub fn language(&self, name: &str) -> Option<&LanguageConfig> {
+        self.languages.get(name).or_else(|| {
+            self.languages.iter().find_map(|(lang, conf)| {
+                std::iter::once(lang)
+                    .chain(&conf.aliases)
+                    .any(|n| n.eq_ignore_ascii_case(name))
+                    .then_some(conf)
+            })
+        })
+    }
+}
+
+fn read_file(path: &Path) -> Result<File> {
+    let contents =
+        std::fs::read_to_string(path).context(format!("Failed to read config file {path:?}"))?;
+    let file = toml::from_str(&contents).context(format!("Invalid config file {path:?}"))?;
+    tracing::info!("Loaded config from {path:?}");
+    Ok(file)
+}
 
-        c
+/// Load and validate the config, this has to be done before anything calls `get`
+pub fn init() -> Result<()> {
+    //load dotenv file if it exists, before clap goes looking at the environment
+    match dotenv() {
+        Err(_) => tracing::info!("No .env file found, nothing to load"),
+        Ok(_) => tracing::info!("Loaded config from .env file"),
     }
+
+    let args = ARGS.get_or_init(Args::parse);
+    let config = Config::load(args.clone())?;
+    *CONFIG.write().unwrap() = Some(Arc::new(config));
+    Ok(())
 }
-pub fn get() -> &'static Config {
-    CONFIG.get_or_init(|| Config::init_from_env().unwrap())
+
+/// Re-read the config file and swap it in if it's valid, returning the old and new configs
+/// Runs that have already started keep the config they were started with
+pub fn reload() -> Result<(Arc<Config>, Arc<Config>)> {
+    let args = ARGS.get().context("Config reloaded before it was loaded")?;
+    let new = Arc::new(Config::load(args.clone())?);
+
+    let mut current = CONFIG.write().unwrap();
+    let old = current
+        .replace(new.clone())
+        .context("Config reloaded before it was loaded")?;
+
+    //the server is already bound by now
+    if old.port != new.port {
+        tracing::warn!(
+            "Port changed from {} to {}, this needs a restart",
+            old.port,
+            new.port
+        );
+    }
+
+    tracing::info!("Config reloaded");
+    Ok((old, new))
+}
+
+/// The current config, hold on to this for as long as you need consistent settings
+pub fn get() -> Arc<Config> {
+    CONFIG
+        .read()
+        .unwrap()
+        .clone()
+        .expect("Config used before it was loaded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(file: &str) -> Result<Config> {
+        let args = Args::parse_from(["pyromaniac", "--resource-path", "/res"]);
+        Config::build(args, toml::from_str(file).unwrap())
+    }
+
+    #[test]
+    fn builtin_languages() {
+        let config = build("").unwrap();
+        let names: Vec<_> = config.languages.keys().collect();
+        assert_eq!(
+            names,
+            [
+                "bash",
+                "c",
+                "cpp",
+                "go",
+                "haskell",
+                "java",
+                "javascript",
+                "kotlin",
+                "ocaml",
+                "python",
+                "rust",
+                "scala",
+                "sh",
+                "sql",
+                "typescript",
+                "wasm"
+            ]
+        );
+
+        let rust = config.language("rust").unwrap();
+        assert_eq!(rust.spec.workdir, Path::new("/cargo_project"));
+        assert!(rust.spec.compile.is_some());
+        assert_eq!(rust.rootfs, Path::new("/res/rootfs-rust.ext4"));
+        assert_eq!(rust.memory, 1024);
+        assert_eq!(rust.spec.artifacts, [Path::new("main")]);
+        //interpreted languages have nothing to cache
+        assert!(config.language("python").unwrap().spec.artifacts.is_empty());
+        //python only has anything to do before running when there's a manifest
+        let python = &config.language("python").unwrap().spec;
+        assert!(python.compile.is_none());
+        assert_eq!(
+            python.install.as_deref().unwrap(),
+            ["/usr/local/bin/pyro-python", "install"]
+        );
+        assert_eq!(config.cache.max_bytes, 1024 * 1024 * 1024);
+        assert_eq!(config.results.path, None);
+        assert_eq!(config.results.idempotency_ttl, Duration::from_secs(86400));
+        //only languages that keep state between snippets have sessions
+        assert_eq!(
+            python.repl.as_ref().unwrap().command,
+            ["/usr/local/bin/pyro-python", "repl", "{deps}"]
+        );
+        assert!(config.language("bash").unwrap().spec.repl.is_some());
+        assert!(rust.spec.repl.is_none());
+        assert_eq!(config.sessions.idle_timeout, Duration::from_secs(300));
+
+        //ghc needs more than the global default, even if the global default is changed
+        let config = build("[global]\nmemory = 512").unwrap();
+        assert_eq!(config.language("hs").unwrap().memory, 2048);
+        assert_eq!(config.language("ml").unwrap().memory, 512);
+    }
+
+    #[test]
+    fn languages_can_share_an_image() {
+        let config = build("").unwrap();
+        let ts = config.language("ts").unwrap();
+        assert_eq!(ts.rootfs, Path::new("/res/rootfs-node.ext4"));
+        assert_eq!(ts.rootfs, config.language("js").unwrap().rootfs);
+
+        //an explicit rootfs still wins
+        let config = build("[languages.typescript]\nrootfs = 'ts.ext4'").unwrap();
+        assert_eq!(
+            config.language("ts").unwrap().rootfs,
+            Path::new("/res/ts.ext4")
+        );
+    }
+
+    #[test]
+    fn vm_size_is_in_vars() {
+        let config = build("[languages.java]\nmemory = 2048\ncpus = 2").unwrap();
+        let java = &config.language("java").unwrap().spec;
+        assert_eq!(java.vars["cpus"], "2");
+        assert_eq!(java.vars["memory"], "2048");
+        assert_eq!(java.vars["heap"], "1344");
+
+        //but the language can still set them itself
+        let config = build("[languages.java.vars]\nheap = '100'").unwrap();
+        assert_eq!(config.language("java").unwrap().spec.vars["heap"], "100");
+        assert_eq!(heap_mib(128), 64);
+    }
+
+    #[test]
+    fn versions_are_installed_images() {
+        let res = tempfile::TempDir::new().unwrap();
+        for image in [
+            "rootfs-python-3.12.ext4",
+            "rootfs-node-20.ext4",
+            "rootfs-cpp.ext4",
+        ] {
+            std::fs::write(res.path().join(image), "").unwrap();
+        }
+        let build = |file: &str| {
+            let args = Args::parse_from([
+                "pyromaniac",
+                "--resource-path",
+                res.path().to_str().unwrap(),
+            ]);
+            Config::build(args, toml::from_str(file).unwrap())
+        };
+
+        let config = build("").unwrap();
+        let python = config.language("python").unwrap();
+        //the unversioned image is still the default
+        assert_eq!(python.version.as_deref(), Some("3.11"));
+        assert_eq!(python.rootfs, res.path().join("rootfs-python.ext4"));
+        assert_eq!(python.versions.keys().collect::<Vec<_>>(), ["3.11", "3.12"]);
+        let py312 = python.version("3.12").unwrap();
+        assert_eq!(py312.rootfs, res.path().join("rootfs-python-3.12.ext4"));
+        assert_eq!(py312.spec.vars["version"], "3.12");
+
+        //shared images share versions, and rootfs-cpp isn't a version of c
+        assert!(config.language("ts").unwrap().version("20").is_some());
+        assert!(config.language("c").unwrap().versions.is_empty());
+
+        let config = build(
+            "[languages.python]\ndefault_version = '3.12'\n\
+             [languages.rust.versions.nightly]\nrootfs = 'nightly.ext4'",
+        )
+        .unwrap();
+        let python = config.language("python").unwrap();
+        assert_eq!(python.version.as_deref(), Some("3.12"));
+        assert!(python.version("3.11").is_some());
+        let nightly = config.language("rust").unwrap().version("nightly").unwrap();
+        assert_eq!(nightly.rootfs, res.path().join("nightly.ext4"));
+        assert_eq!(nightly.spec.env["RUSTFLAGS"], "{rustflags}");
+        assert_eq!(nightly.spec.vars["version"], "nightly");
+
+        let err = build("[languages.python]\ndefault_version = '2.7'").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "languages.python.default_version: \"2.7\" isn't installed, there's: 3.11, 3.12"
+        );
+    }
+
+    #[test]
+    fn options_are_allowlisted() {
+        let config = build("").unwrap();
+        let rust = config.language("rust").unwrap();
+        assert_eq!(rust.spec.vars["edition"], "2021");
+        assert_eq!(rust.spec.vars["rustflags"], "");
+
+        let options: BTreeMap<String, serde_json::Value> = serde_json::from_str(
+            r#"{"edition": 2018, "test": true, "rustflags": ["-C overflow-checks=on", "-C target-cpu=native"]}"#,
+        )
+        .unwrap();
+        let spec = rust.with_options(&options).unwrap();
+        assert_eq!(spec.vars["edition"], "2018");
+        assert_eq!(spec.vars["test"], "true");
+        assert_eq!(spec.vars["profile"], "release");
+        assert_eq!(
+            spec.vars["rustflags"],
+            "-C overflow-checks=on -C target-cpu=native"
+        );
+
+        let with = |json: &str| rust.with_options(&serde_json::from_str(json).unwrap());
+        assert_eq!(
+            with(r#"{"rustflags": ["-C link-arg=/etc/shadow"]}"#).unwrap_err(),
+            "Option \"rustflags\" can't be \"-C link-arg=/etc/shadow\", it can be: \
+             -C overflow-checks=on, -C overflow-checks=off, -C debug-assertions=on, \
+             -C debug-assertions=off, -C target-cpu=native"
+        );
+        assert!(with(r#"{"edition": ["2018"]}"#).is_err());
+        assert!(with(r#"{"opt": "-O3"}"#)
+            .unwrap_err()
+            .starts_with("rust doesn't have an option \"opt\""));
+
+        let cpp = config.language("c++").unwrap();
+        assert_eq!(cpp.spec.vars["opt"], "-O2");
+        let spec = cpp
+            .with_options(&serde_json::from_str(r#"{"opt": "-Os"}"#).unwrap())
+            .unwrap();
+        assert_eq!(spec.vars["opt"], "-Os");
+        assert!(cpp
+            .with_options(&serde_json::from_str(r#"{"opt": "-Ofast"}"#).unwrap())
+            .is_err());
+
+        //the module style is one letter of the extensions
+        let ts = config.language("ts").unwrap();
+        assert_eq!(ts.spec.source, Path::new("code.{module}ts"));
+        assert_eq!(ts.spec.vars["module"], "c");
+        let spec = ts
+            .with_options(&serde_json::from_str(r#"{"module": "m"}"#).unwrap())
+            .unwrap();
+        assert_eq!(spec.vars["module"], "m");
+
+        //dependency sets are options too
+        let python = config.language("python").unwrap();
+        assert_eq!(
+            python.spec.manifest.as_deref(),
+            Some(Path::new("requirements.txt"))
+        );
+        let spec = python
+            .with_options(&serde_json::from_str(r#"{"deps": ["datascience"]}"#).unwrap())
+            .unwrap();
+        assert_eq!(spec.vars["deps"], "datascience");
+
+        let err = build(
+            "[languages.rust.options.profile]\nvalues = ['bench']\ndefault = 'release'\n\
+             [languages.rust.options.memory]\nvalues = ['1']\ndefault = '1'",
+        )
+        .unwrap()
+        .validate()
+        .unwrap_err();
+        assert!(err.to_string().contains(
+            "languages.rust.options.profile.default: \"release\" isn't one of the values"
+        ));
+        assert!(err
+            .to_string()
+            .contains("languages.rust.options.memory: memory is always filled in"));
+    }
+
+    #[test]
+    fn lookup_by_alias_ignores_case() {
+        let config = build("").unwrap();
+        assert_eq!(config.language("Python").unwrap().spec.name, "python");
+        assert_eq!(config.language("PY").unwrap().spec.name, "python");
+        assert!(config.language("cobol").is_none());
+    }
+
+    #[test]
+    fn file_overrides_builtin() {
+        let config = build(
+            r#"
+            [global]
+            memory = 512
+            [languages.rust]
+            memory = 2048
+            [languages.rust.env]
+            RUSTFLAGS = "-C opt-level=1"
+            "#,
+        )
+        .unwrap();
+
+        let rust = config.language("rust").unwrap();
+        assert_eq!(rust.memory, 2048);
+        assert_eq!(rust.spec.env["RUSTFLAGS"], "-C opt-level=1");
+        //the rest of the environment is still there
+        assert_eq!(rust.spec.env["CARGO_HOME"], "/usr/local/cargo");
+        assert_eq!(config.language("python").unwrap().memory, 512);
+    }
+
+    #[test]
+    fn new_language_from_file() {
+        let config = build(
+            r#"
+            [languages.ruby]
+            aliases = ["rb"]
+            workdir = "/tmp"
+            source = "code.rb"
+            run = ["/usr/local/bin/ruby", "{source}"]
+            "#,
+        )
+        .unwrap();
+
+        let ruby = config.language("rb").unwrap();
+        assert_eq!(ruby.spec.name, "ruby");
+        assert_eq!(ruby.spec.compile, None);
+        assert_eq!(ruby.rootfs, Path::new("/res/rootfs-ruby.ext4"));
+    }
+
+    #[test]
+    fn new_language_needs_run() {
+        let err = build(
+            r#"
+            [languages.ruby]
+            workdir = "/tmp"
+            source = "code.rb"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "languages.ruby.run: must be set for languages that aren't built in"
+        );
+    }
+
+    #[test]
+    fn duplicate_aliases() {
+        let config = build(
+            r#"
+            [languages.bash]
+            aliases = ["sh"]
+            "#,
+        )
+        .unwrap();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains(r#"languages.sh: "sh" is already used by languages.bash"#));
+    }
 }
@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// On-disk store for the logs from each run
+/// Laid out as `<log path>/<run id>/<boot attempt>/<file>`, so that failed boots keep their logs too
+#[derive(Debug)]
+pub struct RunLogs {
+    pub id: Uuid,
+    dir: PathBuf,
+}
+
+/// The logs from one boot attempt, keyed by file name
+pub type AttemptLogs = BTreeMap<String, String>;
+
+/// Runs and sessions that are still going, whose logs mustn't be evicted
+static OPEN: Mutex<BTreeSet<Uuid>> = Mutex::new(BTreeSet::new());
+
+impl RunLogs {
+    /// Create the log directory for a new run, evicting the oldest runs in the background
+    /// if the store is full. Both are file I/O, so they're done off the async workers
+    pub async fn new() -> Result<Self> {
+        let config = crate::config::get();
+        let id = Uuid::new_v4();
+        let dir = config.logs.path.join(id.to_string());
+
+        let create = dir.clone();
+        tokio::task::spawn_blocking(move || std::fs::create_dir_all(create))
+            .await?
+            .context(format!("Failed to create log dir {dir:?}"))?;
+        OPEN.lock().unwrap().insert(id);
+
+        //the run doesn't need to wait for it
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = evict(&config.logs.path, config.logs.max_runs) {
+                tracing::warn!("Failed to evict old run logs: {e:#}");
+            }
+        });
+
+        Ok(RunLogs { id, dir })
+    }
+
+    /// Directory for a sandbox to put its logs in for a boot attempt
+    pub fn attempt(&self, attempt: u32) -> Result<PathBuf> {
+        let dir = self.dir.join(attempt.to_string());
+        std::fs::create_dir_all(&dir).context(format!("Failed to create log dir {dir:?}"))?;
+        Ok(dir)
+    }
+
+    /// Cut any oversized files down to size once the run is done with them
+    pub fn finish(&self) {
+        let max = crate::config::get().logs.max_file_bytes;
+        let files = walk(&self.dir).unwrap_or_default();
+        for file in files {
+            if let Err(e) = truncate_head(&file, max) {
+                tracing::warn!("Failed to trim log file {file:?}: {e:#}");
+            }
+        }
+    }
+}
+
+impl Drop for RunLogs {
+    fn drop(&mut self) {
+        OPEN.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Somewhere for a sandbox's console to go, returned as its stdout and stderr
+/// It's written to `path` as it comes in, but cut down to its last `max_file_bytes` whenever
+/// it gets to twice that, so a guest spamming the console can't fill the disk
+pub fn console(path: &Path) -> Result<(Stdio, Stdio)> {
+    let max = crate::config::get().logs.max_file_bytes;
+    let mut file = Capped::create(path, max)?;
+    let (mut reader, writer) = UnixStream::pair().context("Failed to create console pipe")?;
+
+    //until everything in the sandbox holding the other end has exited
+    std::thread::spawn(move || {
+        let mut buf = [0; 8192];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            if let Err(e) = file.write(&buf[..n]) {
+                tracing::warn!("Failed to write console log {:?}: {e:#}", file.path);
+                return;
+            }
+        }
+    });
+
+    let stderr = writer.try_clone()?;
+    Ok((OwnedFd::from(writer).into(), OwnedFd::from(stderr).into()))
+}
+
+/// A log file that's kept to somewhere between `max` and twice that while it's written
+struct Capped {
+    path: PathBuf,
+    file: std::fs::File,
+    len: u64,
+    max: u64,
+}
+
+impl Capped {
+    fn create(path: &Path, max: u64) -> Result<Capped> {
+        let file = std::fs::File::create(path).context(format!("Unable to create {path:?}"))?;
+        Ok(Capped {
+            path: path.to_owned(),
+            file,
+            len: 0,
+            max,
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.file.write_all(bytes)?;
+        self.len += bytes.len() as u64;
+        if self.len > self.max.saturating_mul(2) {
+            truncate_head(&self.path, self.max)?;
+            self.file = std::fs::OpenOptions::new().append(true).open(&self.path)?;
+            //not counting the note about what was cut
+            self.len = self.max;
+        }
+        Ok(())
+    }
+}
+
+/// Cut a log file that was written outside of the log store down to size
+pub fn trim(path: &Path) -> Result<()> {
+    truncate_head(path, crate::config::get().logs.max_file_bytes)
+}
+
+/// Read back all the logs for a run, one map of files per boot attempt
+/// Returns None if there are no logs for the run (or they've been evicted)
+pub fn read(id: Uuid) -> Result<Option<BTreeMap<u32, AttemptLogs>>> {
+    let dir = crate::config::get().logs.path.join(id.to_string());
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+
+    let mut attempts = BTreeMap::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let Some(attempt) = entry.file_name().to_str().and_then(|n| n.parse().ok()) else {
+            continue;
+        };
+        let mut files = AttemptLogs::new();
+        for file in std::fs::read_dir(entry.path())? {
+            let file = file?;
+            let contents = std::fs::read(file.path())?;
+            files.insert(
+                file.file_name().to_string_lossy().into_owned(),
+                String::from_utf8_lossy(&contents).into_owned(),
+            );
+        }
+        attempts.insert(attempt, files);
+    }
+
+    Ok(Some(attempts))
+}
+
+/// Delete the oldest run directories until there are at most `max_runs` left
+/// Ones for runs and sessions that are still going are kept, even if they're the oldest
+fn evict(root: &Path, max_runs: usize) -> Result<()> {
+    let mut runs = std::fs::read_dir(root)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .collect::<Vec<_>>();
+
+    if runs.len() <= max_runs {
+        return Ok(());
+    }
+    let excess = runs.len() - max_runs;
+
+    runs.sort();
+    let open = OPEN.lock().unwrap().clone();
+    let is_open = |dir: &Path| {
+        dir.file_name()
+            .and_then(|name| name.to_str()?.parse().ok())
+            .is_some_and(|id| open.contains(&id))
+    };
+    for (_, dir) in runs.iter().filter(|(_, dir)| !is_open(dir)).take(excess) {
+        std::fs::remove_dir_all(dir).context(format!("Failed to remove {dir:?}"))?;
+    }
+    Ok(())
+}
+
+fn walk(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(walk(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Keep only the last `max` bytes of a file, the end of a log is the interesting bit
+fn truncate_head(path: &Path, max: u64) -> Result<()> {
+    let len = std::fs::metadata(path)?.len();
+    if len <= max {
+        return Ok(());
+    }
+
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(len - max))?;
+    let mut tail = Vec::with_capacity(max as usize);
+    file.read_to_end(&mut tail)?;
+
+    let mut contents = format!("[{} bytes truncated]\n", len - max).into_bytes();
+    contents.extend(tail);
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn console_is_capped_while_written() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("console.log");
+        let mut file = Capped::create(&path, 10).unwrap();
+
+        file.write(b"0123456789abcdefghij").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap().len(), 20);
+        //past twice the limit, only the end is kept
+        file.write(b"XYZ").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "[13 bytes truncated]\ndefghijXYZ"
+        );
+        //and it's another `max` before it's cut again
+        file.write(b"!").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "[13 bytes truncated]\ndefghijXYZ!"
+        );
+    }
+}
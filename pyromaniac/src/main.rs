@@ -1,8 +1,17 @@
 use tracing::{Instrument, Level};
 use tracing_subscriber::fmt::format::FmtSpan;
 mod api;
+mod cache;
 mod config;
+mod logs;
+mod metrics;
+mod reload;
+mod results;
 mod runner;
+mod sessions;
+
+use std::sync::Arc;
+use tokio::sync::Semaphore;
 
 #[tokio::main]
 async fn main() {
@@ -15,10 +24,19 @@ async fn main() {
     )
     .expect("Could not install tracing subscriber");
 
+    if let Err(e) = config::init() {
+        tracing::error!("{e:#}");
+        std::process::exit(1);
+    }
+
+    let vms = Arc::new(Semaphore::new(config::get().runner_config.max_vms));
+    tokio::spawn(reload::on_sighup(vms.clone()));
+    tokio::spawn(sessions::reap());
+
     tracing::info!("Starting web server...");
 
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(api::app().into_make_service())
+    axum::Server::bind(&([0, 0, 0, 0], config::get().port).into())
+        .serve(api::app(vms).into_make_service())
         .instrument(tracing::info_span!("Web server"))
         .await
         .expect("Could not start server");
@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// What firecracker reported about a single VM over its lifetime
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VmMetrics {
+    /// Time from the firecracker process starting to the VMM being ready
+    pub startup_time_us: Option<u64>,
+    pub vcpu_exits_io: u64,
+    pub vcpu_exits_mmio: u64,
+    pub block_read_bytes: u64,
+    pub block_write_bytes: u64,
+    pub block_read_ops: u64,
+    pub block_write_ops: u64,
+    pub vsock_rx_bytes: u64,
+    pub vsock_tx_bytes: u64,
+}
+
+/// Upper bounds of the boot time histogram buckets, in seconds
+const BOOT_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+/// And for compile and run times, which can be a lot longer
+const RUN_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Durations, counted into buckets by their upper bound
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, time: Duration) {
+        self.count.fetch_add(1, Relaxed);
+        self.sum_us.fetch_add(time.as_micros() as u64, Relaxed);
+        for (bucket, le) in self.buckets.iter().zip(self.bounds) {
+            if time.as_secs_f64() <= *le {
+                bucket.fetch_add(1, Relaxed);
+            }
+        }
+    }
+
+    /// The samples, with `labels` (eg `lang="java",`) on each
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bucket, le) in self.buckets.iter().zip(self.bounds) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}le=\"{le}\"}} {}",
+                bucket.load(Relaxed)
+            );
+        }
+        let count = self.count.load(Relaxed);
+        let sum = self.sum_us.load(Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{{labels}le=\"+Inf\"}} {count}");
+        let labels = labels.trim_end_matches(',');
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {count}");
+    }
+}
+
+/// The help and type lines for a histogram
+fn header(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP pyromaniac_{name} {help}");
+    let _ = writeln!(out, "# TYPE pyromaniac_{name} histogram");
+}
+
+/// How long one language's code took to compile and to run
+#[derive(Debug)]
+struct LanguageTimes {
+    compile: Histogram,
+    run: Histogram,
+}
+
+/// Counters for the whole server, exported in prometheus text format
+#[derive(Debug)]
+pub struct Metrics {
+    boot_failures: AtomicU64,
+    boot_time: Histogram,
+    languages: Mutex<BTreeMap<String, LanguageTimes>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    result_cache_hits: AtomicU64,
+    result_cache_misses: AtomicU64,
+    vms_reported: AtomicU64,
+    startup_time_us_sum: AtomicU64,
+    vcpu_exits_io: AtomicU64,
+    vcpu_exits_mmio: AtomicU64,
+    block_read_bytes: AtomicU64,
+    block_write_bytes: AtomicU64,
+    block_read_ops: AtomicU64,
+    block_write_ops: AtomicU64,
+    vsock_rx_bytes: AtomicU64,
+    vsock_tx_bytes: AtomicU64,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn get() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            boot_failures: Default::default(),
+            boot_time: Histogram::new(BOOT_BUCKETS),
+            languages: Default::default(),
+            cache_hits: Default::default(),
+            cache_misses: Default::default(),
+            result_cache_hits: Default::default(),
+            result_cache_misses: Default::default(),
+            vms_reported: Default::default(),
+            startup_time_us_sum: Default::default(),
+            vcpu_exits_io: Default::default(),
+            vcpu_exits_mmio: Default::default(),
+            block_read_bytes: Default::default(),
+            block_write_bytes: Default::default(),
+            block_read_ops: Default::default(),
+            block_write_ops: Default::default(),
+            vsock_rx_bytes: Default::default(),
+            vsock_tx_bytes: Default::default(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Record a sandbox booting successfully, and how long it took for pyrod to connect
+    pub fn record_boot(&self, time: Duration) {
+        self.boot_time.observe(time);
+    }
+
+    /// Record how long a language took to compile (if it does) and run, as timed inside the sandbox
+    pub fn record_run(&self, lang: &str, compile: Option<Duration>, run: Option<Duration>) {
+        let mut languages = self.languages.lock().unwrap();
+        let times = languages
+            .entry(lang.to_owned())
+            .or_insert_with(|| LanguageTimes {
+                compile: Histogram::new(RUN_BUCKETS),
+                run: Histogram::new(RUN_BUCKETS),
+            });
+        if let Some(compile) = compile {
+            times.compile.observe(compile);
+        }
+        if let Some(run) = run {
+            times.run.observe(run);
+        }
+    }
+
+    /// Record whether compiled code was found in the cache, for runs that could have used it
+    pub fn record_cache(&self, hit: bool) {
+        match hit {
+            true => self.cache_hits.fetch_add(1, Relaxed),
+            false => self.cache_misses.fetch_add(1, Relaxed),
+        };
+    }
+
+    /// Record whether a request that asked for a stored result got one
+    pub fn record_result_cache(&self, hit: bool) {
+        match hit {
+            true => self.result_cache_hits.fetch_add(1, Relaxed),
+            false => self.result_cache_misses.fetch_add(1, Relaxed),
+        };
+    }
+
+    pub fn record_boot_failure(&self) {
+        self.boot_failures.fetch_add(1, Relaxed);
+    }
+
+    pub fn record_vm(&self, vm: &VmMetrics) {
+        self.vms_reported.fetch_add(1, Relaxed);
+        self.startup_time_us_sum
+            .fetch_add(vm.startup_time_us.unwrap_or_default(), Relaxed);
+        self.vcpu_exits_io.fetch_add(vm.vcpu_exits_io, Relaxed);
+        self.vcpu_exits_mmio.fetch_add(vm.vcpu_exits_mmio, Relaxed);
+        self.block_read_bytes
+            .fetch_add(vm.block_read_bytes, Relaxed);
+        self.block_write_bytes
+            .fetch_add(vm.block_write_bytes, Relaxed);
+        self.block_read_ops.fetch_add(vm.block_read_ops, Relaxed);
+        self.block_write_ops.fetch_add(vm.block_write_ops, Relaxed);
+        self.vsock_rx_bytes.fetch_add(vm.vsock_rx_bytes, Relaxed);
+        self.vsock_tx_bytes.fetch_add(vm.vsock_tx_bytes, Relaxed);
+    }
+
+    /// Render everything in the prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let mut counter = |name: &str, help: &str, value: &AtomicU64| {
+            //writing to a string can't fail
+            let _ = writeln!(out, "# HELP pyromaniac_{name} {help}");
+            let _ = writeln!(out, "# TYPE pyromaniac_{name} counter");
+            let _ = writeln!(out, "pyromaniac_{name} {}", value.load(Relaxed));
+        };
+
+        counter(
+            "boot_failures_total",
+            "Sandbox boot attempts that failed",
+            &self.boot_failures,
+        );
+        counter(
+            "cache_hits_total",
+            "Runs that used code compiled by an earlier run",
+            &self.cache_hits,
+        );
+        counter(
+            "cache_misses_total",
+            "Runs that had to compile their code",
+            &self.cache_misses,
+        );
+        counter(
+            "result_cache_hits_total",
+            "Requests answered with the result of an earlier run",
+            &self.result_cache_hits,
+        );
+        counter(
+            "result_cache_misses_total",
+            "Requests that asked for a stored result but had to run",
+            &self.result_cache_misses,
+        );
+        counter(
+            "vms_reported_total",
+            "VMs that reported firecracker metrics",
+            &self.vms_reported,
+        );
+        counter(
+            "firecracker_startup_time_microseconds_total",
+            "Total firecracker process startup time",
+            &self.startup_time_us_sum,
+        );
+        counter(
+            "vcpu_exits_io_total",
+            "vCPU exits for port I/O",
+            &self.vcpu_exits_io,
+        );
+        counter(
+            "vcpu_exits_mmio_total",
+            "vCPU exits for MMIO",
+            &self.vcpu_exits_mmio,
+        );
+        counter(
+            "block_read_bytes_total",
+            "Bytes read from rootfs block devices",
+            &self.block_read_bytes,
+        );
+        counter(
+            "block_write_bytes_total",
+            "Bytes written to rootfs block devices",
+            &self.block_write_bytes,
+        );
+        counter(
+            "block_read_ops_total",
+            "Read operations on rootfs block devices",
+            &self.block_read_ops,
+        );
+        counter(
+            "block_write_ops_total",
+            "Write operations on rootfs block devices",
+            &self.block_write_ops,
+        );
+        counter(
+            "vsock_rx_bytes_total",
+            "Bytes received by VMs over vsock",
+            &self.vsock_rx_bytes,
+        );
+        counter(
+            "vsock_tx_bytes_total",
+            "Bytes sent by VMs over vsock",
+            &self.vsock_tx_bytes,
+        );
+
+        header(
+            &mut out,
+            "boot_time_seconds",
+            "Time from spawning a sandbox to pyrod connecting",
+        );
+        self.boot_time
+            .render(&mut out, "pyromaniac_boot_time_seconds", "");
+
+        //per language, as timed inside the sandbox
+        let languages = self.languages.lock().unwrap();
+        header(
+            &mut out,
+            "compile_time_seconds",
+            "Time taken to compile code, for languages that compile",
+        );
+        for (lang, times) in languages.iter() {
+            let labels = format!("lang=\"{lang}\",");
+            times
+                .compile
+                .render(&mut out, "pyromaniac_compile_time_seconds", &labels);
+        }
+        header(&mut out, "run_time_seconds", "Time taken to run code");
+        for (lang, times) in languages.iter() {
+            let labels = format!("lang=\"{lang}\",");
+            times
+                .run
+                .render(&mut out, "pyromaniac_run_time_seconds", &labels);
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_by_language() {
+        let metrics = Metrics::default();
+        metrics.record_boot(Duration::from_millis(200));
+        metrics.record_run("java", Some(Duration::from_millis(700)), None);
+        metrics.record_run("java", Some(Duration::from_millis(300)), None);
+
+        let out = metrics.render();
+        assert!(out.contains("pyromaniac_boot_time_seconds_bucket{le=\"0.25\"} 1\n"));
+        assert!(out.contains("pyromaniac_boot_time_seconds_count 1\n"));
+        assert!(
+            out.contains("pyromaniac_compile_time_seconds_bucket{lang=\"java\",le=\"0.5\"} 1\n")
+        );
+        assert!(out.contains("pyromaniac_compile_time_seconds_bucket{lang=\"java\",le=\"1\"} 2\n"));
+        assert!(out.contains("pyromaniac_compile_time_seconds_sum{lang=\"java\"} 1\n"));
+        assert!(out.contains("pyromaniac_run_time_seconds_count{lang=\"java\"} 0\n"));
+    }
+}
@@ -0,0 +1,79 @@
+use crate::config::Config;
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
+
+/// Reload the config and resize the VM semaphore to the new `max_vms`
+/// Runs that have already started carry on with the config they started with
+pub fn reload(vms: &Arc<Semaphore>) -> Result<Arc<Config>> {
+    let (old, new) = crate::config::reload()?;
+    resize(vms, old.runner_config.max_vms, new.runner_config.max_vms);
+    Ok(new)
+}
+
+fn resize(vms: &Arc<Semaphore>, old_max: usize, new_max: usize) {
+    if new_max > old_max {
+        vms.add_permits(new_max - old_max);
+    } else if new_max < old_max {
+        //we can't take permits back from running VMs, so wait for them to be given back and bin them
+        //anything that queues for a permit in the meantime waits behind this, which is what we want anyway
+        let vms = vms.clone();
+        let shrink = (old_max - new_max) as u32;
+        tokio::spawn(async move {
+            if let Ok(permits) = vms.acquire_many_owned(shrink).await {
+                permits.forget();
+            }
+        });
+    }
+
+    if new_max != old_max {
+        tracing::info!("Max VMs changed from {old_max} to {new_max}");
+    }
+}
+
+/// Reload the config whenever we get a SIGHUP
+pub async fn on_sighup(vms: Arc<Semaphore>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGHUP, config can't be reloaded with it: {e}");
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        tracing::info!("Got SIGHUP, reloading config");
+        if let Err(e) = reload(&vms) {
+            tracing::error!("Failed to reload config, keeping the old one: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn grow() {
+        let vms = Arc::new(Semaphore::new(2));
+        resize(&vms, 2, 5);
+        assert_eq!(vms.available_permits(), 5);
+    }
+
+    #[tokio::test]
+    async fn shrink_waits_for_running_vms() {
+        let vms = Arc::new(Semaphore::new(3));
+        let running = vms.clone().acquire_many_owned(3).await.unwrap();
+
+        resize(&vms, 3, 1);
+        tokio::task::yield_now().await;
+        assert_eq!(vms.available_permits(), 0);
+
+        //once the running VMs finish, only one permit should be left
+        drop(running);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(vms.available_permits(), 1);
+    }
+}
@@ -0,0 +1,381 @@
+//! Responses from earlier runs, kept for two things:
+//! requests with `"cache": true` get the result of the same request run before, without booting a VM,
+//! and a request retried with the same `Idempotency-Key` header gets the response the first one got, if it succeeded.
+//! Both are in memory, and written through to SQLite if there's a path for it so they survive restarts.
+
+use crate::cache::Lru;
+use crate::config::LanguageConfig;
+use anyhow::Result;
+use pyrod_service::{Language, Submission};
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A response as it was sent, JSON body and all
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stored {
+    pub status: u16,
+    pub body: String,
+}
+
+#[derive(Debug)]
+enum Idempotent {
+    /// The first request with the key hasn't finished yet
+    Running {
+        request: String,
+    },
+    Done {
+        request: String,
+        response: Stored,
+    },
+}
+
+/// What to do with a request that has an `Idempotency-Key`
+#[derive(Debug)]
+pub enum Begin {
+    /// It's the first with the key, so run it and then `finish`
+    Run(Pending),
+    /// Send back what the first one got
+    Replay(Stored),
+    /// The first one is still running
+    Running,
+    /// The key was used for a different request
+    Mismatch,
+}
+
+/// A request holding an idempotency key, which is let go if it's dropped before it finishes
+#[derive(Debug)]
+pub struct Pending {
+    key: Option<String>,
+}
+
+impl Pending {
+    /// Keep the response for retries, if it was a success.
+    /// Anything else lets the key go, the same as dropping it, so a retry runs again
+    pub async fn finish(mut self, response: Stored) {
+        if !(200..300).contains(&response.status) {
+            return;
+        }
+        if let Some(key) = self.key.take() {
+            blocking(move |store, config| {
+                store.finish(key, response, now(), config.idempotency_ttl)
+            })
+            .await;
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        //cancelled or failed without a response, so a retry can run it. nothing waits for this
+        if let Some(key) = self.key.take() {
+            tokio::task::spawn_blocking(move || with_store(|store, _| store.keys.remove(&key)));
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    /// Where it was opened from, which can change if the config is reloaded
+    path: Option<PathBuf>,
+    db: Option<Connection>,
+    /// Bodies of successful runs, by the hash of the request
+    results: HashMap<String, String>,
+    lru: Lru,
+    keys: HashMap<String, Idempotent>,
+    /// Finished idempotency keys in the order they expire, with when they were finished
+    expiry: VecDeque<(Duration, String)>,
+}
+
+impl Store {
+    /// Open the store, loading whatever's in the database if there is one
+    fn open(path: Option<&Path>, ttl: Duration) -> Result<Store> {
+        let mut store = Store {
+            path: path.map(Path::to_owned),
+            ..Default::default()
+        };
+        let Some(path) = path else {
+            return Ok(store);
+        };
+
+        let db = Connection::open(path)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS results (key TEXT PRIMARY KEY, body TEXT NOT NULL, used INTEGER NOT NULL);
+             CREATE TABLE IF NOT EXISTS idempotency (key TEXT PRIMARY KEY, request TEXT NOT NULL, status INTEGER NOT NULL, body TEXT NOT NULL, finished INTEGER NOT NULL);",
+        )?;
+        db.execute(
+            "DELETE FROM idempotency WHERE finished < ?1",
+            params![now().saturating_sub(ttl).as_millis() as i64],
+        )?;
+
+        let mut results = db.prepare("SELECT key, body FROM results ORDER BY used")?;
+        for row in results.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            let (key, body): (String, String) = row?;
+            store.lru.insert(key.clone(), 1);
+            store.results.insert(key, body);
+        }
+        drop(results);
+
+        let mut keys = db.prepare(
+            "SELECT key, request, status, body, finished FROM idempotency ORDER BY finished",
+        )?;
+        for row in keys.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })? {
+            let (key, request, status, body, finished): (String, String, u16, String, i64) = row?;
+            let response = Stored { status, body };
+            let finished = Duration::from_millis(finished as u64);
+            store.expiry.push_back((finished, key.clone()));
+            store
+                .keys
+                .insert(key, Idempotent::Done { request, response });
+        }
+        drop(keys);
+
+        tracing::info!(
+            "Loaded {} results and {} idempotency keys from {path:?}",
+            store.results.len(),
+            store.keys.len()
+        );
+        store.db = Some(db);
+        Ok(store)
+    }
+
+    /// Run something against the database if there is one, warning if it fails
+    fn persist(&self, f: impl FnOnce(&Connection) -> rusqlite::Result<usize>) {
+        if let Some(db) = &self.db {
+            if let Err(e) = f(db) {
+                tracing::warn!("Failed to write to the results database: {e}");
+            }
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<String> {
+        if !self.lru.touch(key) {
+            return None;
+        }
+        self.persist(|db| {
+            db.execute(
+                "UPDATE results SET used = ?2 WHERE key = ?1",
+                params![key, now().as_millis() as i64],
+            )
+        });
+        self.results.get(key).cloned()
+    }
+
+    fn put(&mut self, key: String, body: String, max_entries: usize) {
+        self.persist(|db| {
+            db.execute(
+                "INSERT OR REPLACE INTO results (key, body, used) VALUES (?1, ?2, ?3)",
+                params![key, body, now().as_millis() as i64],
+            )
+        });
+        self.lru.insert(key.clone(), 1);
+        self.results.insert(key, body);
+        for key in self.lru.evict(max_entries as u64) {
+            self.results.remove(&key);
+            self.persist(|db| db.execute("DELETE FROM results WHERE key = ?1", params![key]));
+        }
+    }
+
+    /// Drop idempotency keys that finished longer ago than `ttl`
+    fn expire(&mut self, now: Duration, ttl: Duration) {
+        while let Some((finished, _)) = self.expiry.front() {
+            if now.saturating_sub(*finished) < ttl {
+                break;
+            }
+            let (_, key) = self.expiry.pop_front().expect("Front was just there");
+            self.keys.remove(&key);
+            self.persist(|db| db.execute("DELETE FROM idempotency WHERE key = ?1", params![key]));
+        }
+    }
+
+    fn begin(&mut self, key: &str, request: &str, now: Duration, ttl: Duration) -> Begin {
+        self.expire(now, ttl);
+        match self.keys.get(key) {
+            Some(Idempotent::Running { request: r } | Idempotent::Done { request: r, .. })
+                if r != request =>
+            {
+                Begin::Mismatch
+            }
+            Some(Idempotent::Running { .. }) => Begin::Running,
+            Some(Idempotent::Done { response, .. }) => Begin::Replay(response.clone()),
+            None => {
+                let running = Idempotent::Running {
+                    request: request.to_owned(),
+                };
+                self.keys.insert(key.to_owned(), running);
+                Begin::Run(Pending {
+                    key: Some(key.to_owned()),
+                })
+            }
+        }
+    }
+
+    fn finish(&mut self, key: String, response: Stored, now: Duration, ttl: Duration) {
+        let Some(Idempotent::Running { request }) = self.keys.remove(&key) else {
+            return;
+        };
+        self.persist(|db| {
+            db.execute(
+                "INSERT OR REPLACE INTO idempotency (key, request, status, body, finished) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![key, request, response.status, response.body, now.as_millis() as i64],
+            )
+        });
+        self.expiry.push_back((now, key.clone()));
+        self.keys
+            .insert(key, Idempotent::Done { request, response });
+        self.expire(now, ttl);
+    }
+}
+
+static STORE: Mutex<Option<Store>> = Mutex::new(None);
+
+/// Do something with the store, opening it if it hasn't been yet, or if the config moved it
+fn with_store<T>(f: impl FnOnce(&mut Store, &crate::config::ResultsConfig) -> T) -> T {
+    let config = crate::config::get();
+    let config = &config.results;
+    let mut store = STORE.lock().unwrap();
+    if !matches!(&*store, Some(s) if s.path == config.path) {
+        let opened =
+            Store::open(config.path.as_deref(), config.idempotency_ttl).unwrap_or_else(|e| {
+                tracing::warn!(
+                    "Failed to open results database {:?}, keeping results in memory only: {e:#}",
+                    config.path
+                );
+                Store {
+                    path: config.path.clone(),
+                    ..Default::default()
+                }
+            });
+        *store = Some(opened);
+    }
+    f(store.as_mut().expect("Store was just opened"), config)
+}
+
+/// `with_store` off the async workers, because it can be waiting on SQLite or the lock
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce(&mut Store, &crate::config::ResultsConfig) -> T + Send + 'static,
+) -> T {
+    tokio::task::spawn_blocking(move || with_store(f))
+        .await
+        .expect("Results store panicked")
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Hash of everything about a request that could change what it gets back:
+/// everything compiling depends on, the same as the artifact cache, the limits it runs with, and the input
+pub fn key(lang: &LanguageConfig, spec: &Language, submission: &Submission) -> String {
+    let compiled = crate::cache::key(lang, spec, submission);
+    //a run that timed out or was cut short could do better with more
+    let limits = (lang.limits(), lang.cpus, lang.memory);
+    let hashed = (compiled, limits, &submission.input);
+    let bytes = bincode::serialize(&hashed).expect("Can always serialize the result key");
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// The body of a successful run of the same request, if there was one
+pub async fn get(key: &str) -> Option<String> {
+    let key = key.to_owned();
+    blocking(move |store, _| store.get(&key)).await
+}
+
+/// Keep a successful run's body, for the next time the same request asks for it
+pub async fn put(key: &str, body: &str) {
+    let (key, body) = (key.to_owned(), body.to_owned());
+    blocking(move |store, config| store.put(key, body, config.max_entries)).await;
+}
+
+/// Claim an idempotency key for a request, identified by `request` (its hash)
+pub async fn begin(key: &str, request: &str) -> Begin {
+    let (key, request) = (key.to_owned(), request.to_owned());
+    blocking(move |store, config| store.begin(&key, &request, now(), config.idempotency_ttl)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(body: &str) -> Stored {
+        Stored {
+            status: 200,
+            body: body.to_owned(),
+        }
+    }
+
+    /// Finish without going through the global store
+    fn finish(store: &mut Store, pending: Begin, response: Stored, now: Duration) {
+        let Begin::Run(mut pending) = pending else {
+            panic!("Expected to run, got {pending:?}");
+        };
+        let key = pending.key.take().unwrap();
+        store.finish(key, response, now, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn idempotency_keys() {
+        let ttl = Duration::from_secs(60);
+        let t = Duration::from_secs;
+        let mut store = Store::default();
+
+        let first = store.begin("k", "req", t(0), ttl);
+        assert!(matches!(store.begin("k", "req", t(1), ttl), Begin::Running));
+        assert!(matches!(
+            store.begin("k", "other", t(1), ttl),
+            Begin::Mismatch
+        ));
+        finish(&mut store, first, ok("out"), t(2));
+
+        let replay = store.begin("k", "req", t(3), ttl);
+        assert!(matches!(replay, Begin::Replay(r) if r == ok("out")));
+        assert!(matches!(
+            store.begin("k", "other", t(3), ttl),
+            Begin::Mismatch
+        ));
+
+        //forgotten once it expires
+        let Begin::Run(mut again) = store.begin("k", "other", t(62), ttl) else {
+            panic!("Expected to run again");
+        };
+        again.key = None;
+    }
+
+    #[test]
+    fn persisted_in_sqlite() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("results.db");
+        let ttl = Duration::from_secs(3600);
+
+        let mut store = Store::open(Some(&path), ttl).unwrap();
+        store.put("a".to_owned(), "{\"a\":1}".to_owned(), 2);
+        store.put("b".to_owned(), "{\"b\":1}".to_owned(), 2);
+        assert!(store.get("a").is_some());
+        //b is least recently used
+        store.put("c".to_owned(), "{\"c\":1}".to_owned(), 2);
+        assert_eq!(store.get("b"), None);
+        let pending = store.begin("k", "req", now(), ttl);
+        finish(&mut store, pending, ok("out"), now());
+        drop(store);
+
+        let mut store = Store::open(Some(&path), ttl).unwrap();
+        assert_eq!(store.get("a").as_deref(), Some("{\"a\":1}"));
+        assert_eq!(store.get("b"), None);
+        assert!(store.get("c").is_some());
+        let replay = store.begin("k", "req", now(), ttl);
+        assert!(matches!(replay, Begin::Replay(r) if r == ok("out")));
+    }
+}
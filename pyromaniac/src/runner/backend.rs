@@ -0,0 +1,69 @@
+use crate::config::{Config, LanguageConfig};
+use async_trait::async_trait;
+use pyrod_service::PyrodClient;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::time::Duration;
+use tokio::net::UnixListener;
+
+/// The different ways a sandbox can fail to come up
+/// Only the ones where the sandbox started and then didn't come up are retried
+#[derive(Debug, thiserror::Error)]
+pub enum BootError {
+    #[error("Failed to spawn sandbox: {0:#}")]
+    Spawn(anyhow::Error),
+    #[error(
+        "Sandbox process exited during boot{}",
+        .0.map(|s| format!(" with {s}")).unwrap_or_default()
+    )]
+    EarlyExit(Option<ExitStatus>),
+    /// The VM process exited cleanly, which is what it does when the guest shuts down or reboots,
+    /// including when the kernel panics
+    #[error("Guest exited during boot")]
+    GuestExited,
+    #[error("pyrod did not connect within {0:?}")]
+    NoConnect(Duration),
+    #[error("Failed to accept connection from pyrod: {0:#}")]
+    Connect(anyhow::Error),
+}
+
+impl BootError {
+    /// Whether trying again could go differently
+    /// Failing to spawn is usually configuration, like a missing rootfs, which won't fix itself
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            BootError::EarlyExit(_) | BootError::GuestExited | BootError::NoConnect(_)
+        )
+    }
+}
+
+/// A connection to pyrod running in a sandbox
+/// The listener has to be kept alive for as long as the client is in use
+pub type Connection = (PyrodClient, UnixListener);
+
+/// Something that can create isolated environments for pyrod to run code in
+#[async_trait]
+pub trait SandboxBackend: Send + Sync {
+    /// Create a new sandbox for running `lang` and start pyrod inside it
+    /// Settings come from `config` rather than the global one, so a reload doesn't change them mid-run
+    /// Anything worth keeping for debugging (console output, VMM logs) should end up in `logs`
+    /// The socket pyrod connects back on has to be listening before pyrod is started, as it only tries once
+    async fn spawn(
+        &self,
+        config: &Config,
+        lang: &LanguageConfig,
+        logs: &Path,
+    ) -> Result<Box<dyn Sandbox>, BootError>;
+}
+
+/// A running sandbox, which should be torn down when dropped
+#[async_trait]
+pub trait Sandbox: Send {
+    /// Wait for pyrod in the sandbox to connect back to us
+    /// Should return early if the sandbox dies before pyrod connects
+    async fn connect(&mut self) -> Result<Connection, BootError>;
+
+    /// Kill everything in the sandbox and clean up after it
+    async fn teardown(self: Box<Self>);
+}
@@ -1,42 +1,40 @@
 use anyhow::Context;
 use anyhow::Result;
 use serde_json::json;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use tokio::io::AsyncWriteExt;
 
 #[derive(Debug)]
-pub struct VmConfig {
-    pub rootfs: PathBuf,
-    pub kernel: PathBuf,
-    pub runner: &'static crate::config::RunnerConfig,
+pub struct VmConfig<'a> {
+    pub language: &'a crate::config::LanguageConfig,
+    pub runner: &'a crate::config::RunnerConfig,
+    pub resource_path: &'a Path,
 }
 
-impl VmConfig {
+impl VmConfig<'_> {
     #[tracing::instrument]
     pub async fn write_to_file<P: AsRef<Path> + std::fmt::Debug>(&self, chroot: P) -> Result<()> {
         let chroot = chroot.as_ref();
 
         //generate all the json that we need to dump to file
 
-        //disable stty if we don't need it, only used for writing logs in debug mode
-        let boot_args = if cfg!(debug_assertions) {
-            "init=/bin/pyrod console=ttyS0 reboot=k panic=1 pci=off random.trust_cpu=on"
-        } else {
-            "init=/bin/pyrod reboot=k panic=1 pci=off random.trust_cpu=on"
-        };
-
+        //the serial console (console=ttyS0 by default) is kept as part of the run's logs
         let boot_source = json!({
             "kernel_image_path": "kernel.bin",
-            "boot_args":boot_args,
+            "boot_args": self.language.boot_args,
         });
 
         let drive = {
             let file_name = self
+                .language
                 .rootfs
                 .file_name()
                 .and_then(|f| f.to_str())
                 .unwrap_or_else(|| {
-                    panic!("The filename for your rootfs is fucked: {:?}", self.rootfs)
+                    panic!(
+                        "The filename for your rootfs is fucked: {:?}",
+                        self.language.rootfs
+                    )
                 });
 
             json!({
@@ -48,8 +46,8 @@ impl VmConfig {
         };
 
         let machine_config = json!({
-            "vcpu_count": self.runner.cpus,
-            "mem_size_mib": self.runner.memory,
+            "vcpu_count": self.language.cpus,
+            "mem_size_mib": self.language.memory,
             "smt": false
         });
         let vsock = json!({
@@ -60,29 +58,25 @@ impl VmConfig {
 
         let logger = json!({
             "log_path": "firecracker.log",
-            "level": "Debug",
+            "level": if cfg!(debug_assertions) { "Debug" } else { "Info" },
             "show_level": true,
             "show_log_origin": true
         });
 
-        //don't write logs in release mode
-        let config_json = if cfg!(debug_assertions) {
-            //actual final json object
-            json!({
-                "boot-source": boot_source,
-                "drives": [drive],
-                "machine-config": machine_config,
-                "vsock": vsock,
-                "logger": logger
-            })
-        } else {
-            json!({
-                "boot-source": boot_source,
-                "drives": [drive],
-                "machine-config": machine_config,
-                "vsock": vsock
-            })
-        };
+        //a JSON object per line each time firecracker flushes metrics, read as they're written
+        let metrics = json!({
+            "metrics_path": "metrics.fifo"
+        });
+
+        //actual final json object
+        let config_json = json!({
+            "boot-source": boot_source,
+            "drives": [drive],
+            "machine-config": machine_config,
+            "vsock": vsock,
+            "metrics": metrics,
+            "logger": logger
+        });
 
         //dump it to file
         let config_path = chroot.join("config.json");
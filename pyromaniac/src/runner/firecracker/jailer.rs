@@ -0,0 +1,249 @@
+use crate::config::JailerConfig;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Generate a new id for a jailed VM
+/// These need to be unique across every VM on the host, not just within our tempdir,
+/// because jailer uses them to name the cgroup it creates for the VM.
+/// Including our pid keeps them unique if a previous server left cgroups behind.
+pub fn new_id() -> String {
+    format!(
+        "pyro-{}-{}",
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Where jailer will put the chroot for a VM
+pub fn chroot_dir(base: &Path, id: &str) -> PathBuf {
+    base.join("firecracker").join(id).join("root")
+}
+
+/// The cgroup jailer creates for a VM, which it doesn't clean up after itself
+pub fn cgroup_dir(id: &str) -> PathBuf {
+    PathBuf::from("/sys/fs/cgroup/firecracker").join(id)
+}
+
+/// Kill everything in a VM's cgroup
+/// Unlike a pid, the cgroup is only ever ours, so this can't hit anything else
+pub fn kill(cgroup: &Path) -> std::io::Result<()> {
+    match std::fs::write(cgroup.join("cgroup.kill"), "1") {
+        //jailer didn't get as far as making it
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+/// Whether anything in a VM's cgroup is still running
+pub async fn populated(cgroup: &Path) -> std::io::Result<bool> {
+    match tokio::fs::read_to_string(cgroup.join("cgroup.events")).await {
+        Ok(events) => Ok(events.lines().any(|line| line == "populated 1")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Build the argument list for jailer, including the firecracker args after the `--`
+/// The cgroup limits are sized for a VM with `cpus` vCPUs and `memory` MiB of guest memory
+pub fn args(
+    id: &str,
+    config: &JailerConfig,
+    (uid, gid): (u16, u16),
+    (cpus, memory): (u32, u32),
+    firecracker: &Path,
+    chroot_base: &Path,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![
+        "--id".into(),
+        id.into(),
+        "--exec-file".into(),
+        firecracker.into(),
+        "--uid".into(),
+        uid.to_string().into(),
+        "--gid".into(),
+        gid.to_string().into(),
+        "--chroot-base-dir".into(),
+        chroot_base.into(), //actual chroot is base_dir/firecracker/<id>/root
+        "--cgroup-version".into(),
+        "2".into(),
+    ];
+
+    if config.limit_cpu {
+        let quota = cpus as u64 * config.cpu_period_us;
+        args.push("--cgroup".into());
+        args.push(format!("cpu.max={quota} {}", config.cpu_period_us).into());
+    }
+
+    if let Some(overhead) = config.memory_overhead_mib {
+        let mem = (memory + overhead) as u64;
+        args.push("--cgroup".into());
+        args.push(format!("memory.max={}", mem * 1024 * 1024).into());
+    }
+
+    if let Some(fsize) = config.fsize {
+        args.push("--resource-limit".into());
+        args.push(format!("fsize={fsize}").into());
+    }
+
+    if let Some(no_file) = config.no_file {
+        args.push("--resource-limit".into());
+        args.push(format!("no-file={no_file}").into());
+    }
+
+    if let Some(netns) = &config.netns {
+        args.push("--netns".into());
+        args.push(netns.into());
+    }
+
+    if config.daemonize {
+        args.push("--daemonize".into());
+    }
+
+    if config.new_pid_ns {
+        args.push("--new-pid-ns".into());
+    }
+
+    //firecracker args go after this
+    args.extend(["--", "--no-api", "--config-file", "config.json"].map(OsString::from));
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> JailerConfig {
+        JailerConfig {
+            limit_cpu: false,
+            cpu_period_us: 100_000,
+            memory_overhead_mib: None,
+            fsize: None,
+            no_file: None,
+            netns: None,
+            daemonize: false,
+            new_pid_ns: false,
+        }
+    }
+
+    fn args_for(config: &JailerConfig) -> Vec<String> {
+        args(
+            "pyro-1-0",
+            config,
+            (222, 333),
+            (2, 1024),
+            Path::new("/res/firecracker"),
+            Path::new("/tmp/base"),
+        )
+        .into_iter()
+        .map(|a| a.into_string().unwrap())
+        .collect()
+    }
+
+    /// Find the value following each occurrence of `flag`
+    fn values<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+        args.windows(2)
+            .filter(|w| w[0] == flag)
+            .map(|w| w[1].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn minimal_args() {
+        assert_eq!(
+            args_for(&config()),
+            [
+                "--id",
+                "pyro-1-0",
+                "--exec-file",
+                "/res/firecracker",
+                "--uid",
+                "222",
+                "--gid",
+                "333",
+                "--chroot-base-dir",
+                "/tmp/base",
+                "--cgroup-version",
+                "2",
+                "--",
+                "--no-api",
+                "--config-file",
+                "config.json",
+            ]
+        );
+    }
+
+    #[test]
+    fn gid_is_not_uid() {
+        let args = args_for(&config());
+        assert_eq!(values(&args, "--uid"), ["222"]);
+        assert_eq!(values(&args, "--gid"), ["333"]);
+    }
+
+    #[test]
+    fn cgroup_limits() {
+        let args = args_for(&JailerConfig {
+            limit_cpu: true,
+            cpu_period_us: 50_000,
+            memory_overhead_mib: Some(128),
+            ..config()
+        });
+        assert_eq!(
+            values(&args, "--cgroup"),
+            ["cpu.max=100000 50000", "memory.max=1207959552"]
+        );
+    }
+
+    #[test]
+    fn resource_limits() {
+        let args = args_for(&JailerConfig {
+            fsize: Some(1 << 30),
+            no_file: Some(2048),
+            ..config()
+        });
+        assert_eq!(
+            values(&args, "--resource-limit"),
+            ["fsize=1073741824", "no-file=2048"]
+        );
+    }
+
+    #[test]
+    fn netns_and_flags() {
+        let args = args_for(&JailerConfig {
+            netns: Some("/var/run/netns/pyro".into()),
+            daemonize: true,
+            new_pid_ns: true,
+            ..config()
+        });
+        assert_eq!(values(&args, "--netns"), ["/var/run/netns/pyro"]);
+        assert!(args.contains(&"--daemonize".to_owned()));
+        assert!(args.contains(&"--new-pid-ns".to_owned()));
+    }
+
+    #[test]
+    fn jailer_flags_come_before_firecracker_args() {
+        let args = args_for(&JailerConfig {
+            netns: Some("/var/run/netns/pyro".into()),
+            daemonize: true,
+            new_pid_ns: true,
+            ..config()
+        });
+        let sep = args.iter().position(|a| a == "--").unwrap();
+        assert_eq!(
+            &args[sep + 1..],
+            ["--no-api", "--config-file", "config.json"]
+        );
+        assert!(args[..sep].contains(&"--new-pid-ns".to_owned()));
+    }
+
+    #[test]
+    fn ids_are_unique() {
+        let a = new_id();
+        let b = new_id();
+        assert_ne!(a, b);
+        assert!(a.starts_with(&format!("pyro-{}-", std::process::id())));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::metrics::VmMetrics;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::unix::pipe;
+use tokio::sync::oneshot;
+
+/// How long to wait for more once we've been told to stop
+const DRAIN: Duration = Duration::from_millis(100);
+
+/// Make the FIFO for firecracker to write metrics to, and open our end of it
+/// It's opened read-write so we don't see end of file before firecracker has opened it
+pub fn fifo(path: &Path) -> Result<pipe::Receiver> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    //SAFETY - just a syscall, with a nul-terminated path
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o666) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .context(format!("Failed to create metrics FIFO {path:?}"));
+    }
+    //writable by the jailed firecracker process, whatever the umask was
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o666))
+        .context("Could not set perms for metrics FIFO")?;
+    pipe::OpenOptions::new()
+        .read_write(true)
+        .open_receiver(path)
+        .context("Failed to open metrics FIFO")
+}
+
+/// Sum up the metrics firecracker writes while the VM runs, until told to stop
+/// Reading as it goes means a VM that's killed still has whatever it flushed before then
+pub async fn read(fifo: pipe::Receiver, mut stop: oneshot::Receiver<()>) -> VmMetrics {
+    let mut metrics = VmMetrics::default();
+    let mut lines = BufReader::new(fifo).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => add(&mut metrics, &line),
+                Ok(None) => return metrics,
+                Err(e) => {
+                    tracing::warn!("Failed to read firecracker metrics: {e}");
+                    return metrics;
+                }
+            },
+            _ = &mut stop => break,
+        }
+    }
+    //whatever's still in the pipe, which we might not have been woken up for yet
+    while let Ok(Ok(Some(line))) = tokio::time::timeout(DRAIN, lines.next_line()).await {
+        add(&mut metrics, &line);
+    }
+    metrics
+}
+
+/// Add one flush to the totals
+/// Firecracker writes one JSON object per line each time it flushes.
+/// Counters are the change since the previous flush, so they're summed,
+/// and anything missing (which varies between firecracker versions) is counted as zero.
+fn add(metrics: &mut VmMetrics, line: &str) {
+    if line.trim().is_empty() {
+        return;
+    }
+    let json: Value = match serde_json::from_str(line) {
+        Ok(json) => json,
+        Err(e) => {
+            tracing::warn!("Could not parse firecracker metrics line: {e}");
+            return;
+        }
+    };
+    let get = |section: &str, key: &str| json[section][key].as_u64().unwrap_or_default();
+
+    //this one is a gauge not a counter, it's set once at startup
+    if let Some(t) = json["api_server"]["process_startup_time_us"].as_u64() {
+        metrics.startup_time_us = Some(t);
+    }
+    metrics.vcpu_exits_io += get("vcpu", "exit_io_in") + get("vcpu", "exit_io_out");
+    metrics.vcpu_exits_mmio += get("vcpu", "exit_mmio_read") + get("vcpu", "exit_mmio_write");
+    metrics.block_read_bytes += get("block", "read_bytes");
+    metrics.block_write_bytes += get("block", "write_bytes");
+    metrics.block_read_ops += get("block", "read_count");
+    metrics.block_write_ops += get("block", "write_count");
+    metrics.vsock_rx_bytes += get("vsock", "rx_bytes_count");
+    metrics.vsock_tx_bytes += get("vsock", "tx_bytes_count");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn sums_flushes() {
+        let lines = r#"
+{"utc_timestamp_ms":1,"api_server":{"process_startup_time_us":1200},"block":{"read_bytes":4096,"write_bytes":0,"read_count":1,"write_count":0},"vcpu":{"exit_io_in":10,"exit_io_out":5,"exit_mmio_read":1,"exit_mmio_write":2},"vsock":{"rx_bytes_count":100,"tx_bytes_count":50}}
+not json
+{"utc_timestamp_ms":2,"api_server":{"process_startup_time_us":1200},"block":{"read_bytes":4096,"write_bytes":512,"read_count":1,"write_count":1},"vcpu":{"exit_io_in":1},"vsock":{}}
+"#;
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("metrics.fifo");
+        let receiver = fifo(&path).unwrap();
+
+        //written and gone before we stop, like a VM that's exited
+        let mut writer = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        writer.write_all(lines.as_bytes()).unwrap();
+        drop(writer);
+        let (stop, stopped) = oneshot::channel();
+        stop.send(()).unwrap();
+
+        assert_eq!(
+            read(receiver, stopped).await,
+            VmMetrics {
+                startup_time_us: Some(1200),
+                vcpu_exits_io: 16,
+                vcpu_exits_mmio: 3,
+                block_read_bytes: 8192,
+                block_write_bytes: 512,
+                block_read_ops: 2,
+                block_write_ops: 1,
+                vsock_rx_bytes: 100,
+                vsock_tx_bytes: 50,
+            }
+        );
+    }
+}
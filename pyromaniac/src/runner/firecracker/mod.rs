@@ -1,20 +1,53 @@
 mod config;
+mod jailer;
+mod metrics;
 pub use config::VmConfig;
 
-use anyhow::{Context, Result};
+use super::backend::{BootError, Connection, Sandbox, SandboxBackend};
+use super::pyrod_client;
+use crate::config::{Config, LanguageConfig};
+use crate::metrics::VmMetrics;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
 use std::os::unix::fs::PermissionsExt;
 use std::{
-    os::fd::{FromRawFd, IntoRawFd},
     path::{Path, PathBuf},
-    process::Stdio,
+    process::{ExitStatus, Stdio},
+    time::Duration,
 };
 use tempfile::TempDir;
+use tokio::net::UnixListener;
 use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Work out why the VM process exited before pyrod connected
+/// Firecracker exits cleanly when the guest shuts down or reboots, which with `panic=1 reboot=k`
+/// includes a kernel panic (or pyrod dying as init). console.log says which it was.
+/// Any other exit is firecracker or jailer itself falling over.
+/// We can't see the exit status of a VM that jailer moved into its own pid namespace.
+fn exit_error(status: Option<ExitStatus>) -> BootError {
+    match status {
+        Some(s) if s.success() => BootError::GuestExited,
+        _ => BootError::EarlyExit(status),
+    }
+}
 
 /// Holds the resources for our virtual machine
 /// When dropped, will kill the process and clean up all temp resources
 pub struct Machine {
-    _process: Child,
+    process: Child,
+    /// Whether jailer forked firecracker into a new pid namespace
+    /// In that case `process` is the jailer, which exits once firecracker is started
+    detached: bool,
+    /// Taken once it's been killed and removed
+    cgroup: Option<PathBuf>,
+    /// Where to keep this VM's logs once it's gone, taken once they've been saved
+    log_dir: Option<PathBuf>,
+    /// Bound before the VM starts, and handed over once pyrod connects
+    listener: Option<UnixListener>,
+    /// Stops the task reading the metrics FIFO, which returns the totals
+    metrics: Option<(oneshot::Sender<()>, JoinHandle<VmMetrics>)>,
     _dir: TempDir,
     pub chroot: PathBuf,
 }
@@ -25,15 +58,17 @@ impl Machine {
     //see https://github.com/firecracker-microvm/firecracker/issues/1477
     //when using jailer, we have to put everything at
     // /tmp/<tempdir>/firecracker/<vm_id>/root
-    // can always use the same vm_id because we're using different tempdir roots
-    pub async fn spawn(conf: VmConfig) -> Result<Self> {
+    // vm_id has to be unique even though we're using different tempdir roots,
+    // because jailer also uses it for the VM's cgroup
+    pub async fn spawn(conf: VmConfig<'_>, log_dir: &Path) -> Result<Self> {
         //create directory to put all our shit in
         let tempdir = TempDir::new().context("Failed to create tempdir")?;
+        let id = jailer::new_id();
 
         let chroot = if cfg!(debug_assertions) {
             tempdir.path().into()
         } else {
-            tempdir.path().join("firecracker").join("1").join("root")
+            jailer::chroot_dir(tempdir.path(), &id)
         };
 
         std::fs::create_dir_all(&chroot)
@@ -48,20 +83,37 @@ impl Machine {
 
         tracing::debug!("Config file for VM at {:?} written", &chroot);
 
-        //we have to create the logfile before firecracker can use it
-        tokio::fs::File::create(&chroot.join("firecracker.log"))
+        //we have to create the log file before firecracker can use it
+        //and it needs to be writable by the jailed firecracker process
+        tokio::fs::File::create(chroot.join("firecracker.log"))
             .await
-            .context("Unable to create log file")?;
+            .context("Unable to create firecracker.log")?;
+        std::fs::set_permissions(
+            chroot.join("firecracker.log"),
+            std::fs::Permissions::from_mode(0o666),
+        )
+        .context("Could not set perms for firecracker.log")?;
+
+        //metrics are read while the VM runs, so we get them even if it's killed
+        let fifo = metrics::fifo(&chroot.join("metrics.fifo"))?;
+        let (stop, stopped) = oneshot::channel();
+        let reader = tokio::spawn(metrics::read(fifo, stopped));
 
-        let rootfs_file_name = conf
-            .rootfs
-            .file_name()
-            .unwrap_or_else(|| panic!("The filename for your rootfs is fucked: {:?}", conf.rootfs));
+        //the VM's serial console comes out of firecracker's stdout
+        //it's not in the chroot so it survives the VM, and we get it even if firecracker falls over
+        let (stdout, stderr) = crate::logs::console(&log_dir.join("console.log"))?;
+
+        let rootfs_file_name = conf.language.rootfs.file_name().unwrap_or_else(|| {
+            panic!(
+                "The filename for your rootfs is fucked: {:?}",
+                conf.language.rootfs
+            )
+        });
 
         let tmp_rootfs_path = chroot.join(rootfs_file_name);
 
         //create a copy of rootfs
-        tokio::fs::copy(&conf.rootfs, tmp_rootfs_path)
+        tokio::fs::copy(&conf.language.rootfs, tmp_rootfs_path)
             .await
             .context("Failed to copy rootfs into tempdir")?;
 
@@ -71,11 +123,8 @@ impl Machine {
         //to save a copy we can just hard link it
         //we *do* have to copy rootfs tho because those are modified between runs
         //this may fail if /tmp is not mounted in the host's root filesystem
-        std::fs::hard_link(
-            crate::config::get().resource_path.join("kernel.bin"),
-            chroot.join("kernel.bin"),
-        )
-        .context("Failed to hard link kernel into chroot. Is /tmp mounted as a tmpfs?")?;
+        std::fs::hard_link(&conf.language.kernel, chroot.join("kernel.bin"))
+            .context("Failed to hard link kernel into chroot. Is /tmp mounted as a tmpfs?")?;
 
         //mark kernel as executable by anyone (firecracker runs under different uid)
         //TODO: chown instead?
@@ -92,71 +141,252 @@ impl Machine {
         )
         .expect("Could not set perms for rootfs");
 
+        //firecracker connects the guest's port 5000 to <uds_path>_5000
+        let listener = pyrod_client::listen(chroot.join("pyrod.sock_5000"))?;
+
         //spawn firecracker process
         //use jailer in release mode, firecracker in debug
-        let child = if cfg!(debug_assertions) {
-            Command::new(crate::config::get().resource_path.join("firecracker"))
+        let mut child = if cfg!(debug_assertions) {
+            Command::new(conf.resource_path.join("firecracker"))
                 .current_dir(&chroot)
                 .arg("--no-api")
                 .arg("--config-file")
-                .arg(&chroot.join("config.json"))
+                .arg(chroot.join("config.json"))
                 .kill_on_drop(true) //IMPORTANT - for process to be killed
                 .stdin(Stdio::null())
-                .stdout(unsafe {
-                    //SAFETY - file is open and valid because we just opened it
-                    Stdio::from_raw_fd(std::fs::File::create("vm.out")?.into_raw_fd())
-                })
-                .stderr(unsafe {
-                    Stdio::from_raw_fd(std::fs::File::create("vm.err")?.into_raw_fd())
-                })
+                .stdout(stdout)
+                .stderr(stderr)
                 .spawn()
                 .context("Failed to spawn Firecracker process")?
         } else {
-            Command::new(crate::config::get().resource_path.join("jailer"))
+            let ids = (
+                conf.runner
+                    .uid
+                    .expect("No uid provided, cannot start jailer"),
+                conf.runner
+                    .gid
+                    .expect("No gid provided, cannot start jailer"),
+            );
+            Command::new(conf.resource_path.join("jailer"))
                 .current_dir(&chroot)
-                .arg("--id")
-                .arg("1")
-                .arg("--exec-file")
-                .arg(crate::config::get().resource_path.join("firecracker"))
-                .arg("--uid")
-                .arg(
-                    crate::config::get()
-                        .runner_config
-                        .uid
-                        .expect("No uid provided, cannot start jailer")
-                        .to_string(),
-                )
-                .arg("--gid")
-                .arg(
-                    crate::config::get()
-                        .runner_config
-                        .uid
-                        .expect("No gid provided, cannot start jailer")
-                        .to_string(),
-                )
-                .arg("--chroot-base-dir")
-                .arg(tempdir.path()) //actual chroot is base_dir/firecracker/vm/root
-                .arg("--") //firecracker args go after this
-                .arg("--no-api")
-                .arg("--config-file")
-                .arg("config.json")
+                .args(jailer::args(
+                    &id,
+                    &conf.runner.jailer,
+                    ids,
+                    (conf.language.cpus, conf.language.memory),
+                    &conf.resource_path.join("firecracker"),
+                    tempdir.path(),
+                ))
                 .kill_on_drop(true) //IMPORTANT - for process to be killed
                 .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
+                .stdout(stdout)
+                .stderr(stderr)
                 .spawn()
                 .context("Failed to spawn Jailer/Firecracker process")?
         };
 
+        //in a new pid namespace, jailer forks firecracker off and exits
+        //after that firecracker is only found through its cgroup
+        let detached = !cfg!(debug_assertions) && conf.runner.jailer.new_pid_ns;
+        if detached {
+            let status = child.wait().await.context("Failed to wait for jailer")?;
+            if !status.success() {
+                bail!("Jailer exited with {status}");
+            }
+        }
+
         tracing::info!("VM at path {:?} started", chroot);
 
-        //check firecracker did not insta exit
         let machine = Machine {
-            _process: child,
+            process: child,
+            detached,
+            cgroup: (!cfg!(debug_assertions)).then(|| jailer::cgroup_dir(&id)),
+            log_dir: Some(log_dir.to_owned()),
+            listener: Some(listener),
+            metrics: Some((stop, reader)),
             _dir: tempdir,
             chroot,
         };
 
         Ok(machine)
     }
+
+    /// Wait for the VM process to exit
+    /// The exit status is only available if firecracker is our child
+    pub async fn wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        match (&self.cgroup, self.detached) {
+            (Some(cgroup), true) => {
+                //not our child, so can't waitpid on it
+                while jailer::populated(cgroup).await? {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                Ok(None)
+            }
+            _ => self.process.wait().await.map(Some),
+        }
+    }
+}
+
+/// Copy firecracker's log out of the chroot, which is about to be deleted
+/// The run's logs might have been trimmed already, so it's trimmed itself
+fn save_log(chroot: &Path, log_dir: &Path) {
+    let saved = log_dir.join("firecracker.log");
+    if let Err(e) = std::fs::copy(chroot.join("firecracker.log"), &saved)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| crate::logs::trim(&saved))
+    {
+        tracing::warn!("Failed to save firecracker log: {e:#}");
+    }
+}
+
+/// Only for a VM that wasn't torn down, eg because the run was cancelled
+/// Nothing can be waited for here, so it's best effort
+impl Drop for Machine {
+    fn drop(&mut self) {
+        //kill_on_drop only covers the process we spawned
+        if let Some(cgroup) = &self.cgroup {
+            if let Err(e) = jailer::kill(cgroup) {
+                tracing::warn!("Failed to kill VM cgroup {cgroup:?}: {e}");
+            }
+        }
+        if let Some(log_dir) = self.log_dir.take() {
+            save_log(&self.chroot, &log_dir);
+        }
+        //it can't be removed until what was in it has gone, which is usually by now
+        if let Some(cgroup) = self.cgroup.take() {
+            if let Err(e) = std::fs::remove_dir(&cgroup) {
+                tracing::warn!("Failed to clean up cgroup {cgroup:?}: {e}");
+            }
+        }
+    }
+}
+
+/// How long to give a VM to shut itself down before killing it
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// How long to wait for everything in a cgroup to die once it's been killed
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// Kill what's left in a VM's cgroup, and remove it once it's empty, since jailer leaves it behind
+async fn remove_cgroup(cgroup: &Path) {
+    if let Err(e) = jailer::kill(cgroup) {
+        tracing::warn!("Failed to kill VM cgroup {cgroup:?}: {e}");
+        return;
+    }
+    let empty = async {
+        while jailer::populated(cgroup).await? {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::io::Result::Ok(())
+    };
+    match tokio::time::timeout(KILL_GRACE, empty).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => tracing::warn!("Failed to read VM cgroup {cgroup:?}: {e}"),
+        Err(_) => tracing::warn!("VM cgroup {cgroup:?} still populated after {KILL_GRACE:?}"),
+    }
+    match tokio::fs::remove_dir(cgroup).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            tracing::warn!("Failed to clean up cgroup {cgroup:?}: {e}")
+        }
+        _ => {}
+    }
+}
+
+/// Runs each sandbox as a Firecracker microVM, under jailer in release mode
+pub struct FirecrackerBackend;
+
+#[async_trait]
+impl SandboxBackend for FirecrackerBackend {
+    async fn spawn(
+        &self,
+        config: &Config,
+        lang: &LanguageConfig,
+        logs: &Path,
+    ) -> Result<Box<dyn Sandbox>, BootError> {
+        let config = VmConfig {
+            language: lang,
+            runner: &config.runner_config,
+            resource_path: &config.resource_path,
+        };
+
+        let machine = Machine::spawn(config, logs)
+            .await
+            .map_err(BootError::Spawn)?;
+        tracing::debug!("VM process spawned, chroot at {:?}", machine.chroot);
+
+        Ok(Box::new(machine))
+    }
+}
+
+#[async_trait]
+impl Sandbox for Machine {
+    async fn connect(&mut self) -> Result<Connection, BootError> {
+        let listener = self
+            .listener
+            .take()
+            .ok_or_else(|| BootError::Connect(anyhow::anyhow!("Already connected")))?;
+
+        //race pyrod connecting against the VM process exiting
+        //if firecracker goes away there's no point waiting out the timeout
+        tokio::select! {
+            client = pyrod_client::accept(&listener) => match client {
+                Ok(client) => Ok((client, listener)),
+                Err(e) => Err(BootError::Connect(e)),
+            },
+            status = self.wait() => match status {
+                Ok(status) => Err(exit_error(status)),
+                Err(e) => Err(BootError::Connect(e.into())),
+            },
+        }
+    }
+
+    async fn teardown(mut self: Box<Self>) {
+        //pyrod reboots the VM once we hang up, and firecracker only flushes
+        //its last metrics if it gets to exit cleanly like that
+        if tokio::time::timeout(SHUTDOWN_GRACE, self.wait())
+            .await
+            .is_err()
+        {
+            tracing::debug!("VM did not shut down within {:?}", SHUTDOWN_GRACE);
+        }
+
+        //killing the jailer is fine if it's already exited, firecracker goes with its cgroup
+        if let Err(e) = self.process.kill().await {
+            tracing::warn!("Failed to kill VM process: {e}");
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            remove_cgroup(&cgroup).await;
+        }
+
+        let chroot = self.chroot.clone();
+        if let Some(log_dir) = self.log_dir.take() {
+            let saved = tokio::task::spawn_blocking(move || save_log(&chroot, &log_dir)).await;
+            if let Err(e) = saved {
+                tracing::warn!("Failed to save firecracker log: {e}");
+            }
+        }
+
+        let Some((stop, reader)) = self.metrics.take() else {
+            return;
+        };
+        let _ = stop.send(());
+        match reader.await {
+            Ok(vm) => {
+                tracing::info!(
+                    startup_time_us = vm.startup_time_us,
+                    vcpu_exits_io = vm.vcpu_exits_io,
+                    vcpu_exits_mmio = vm.vcpu_exits_mmio,
+                    block_read_bytes = vm.block_read_bytes,
+                    block_write_bytes = vm.block_write_bytes,
+                    block_read_ops = vm.block_read_ops,
+                    block_write_ops = vm.block_write_ops,
+                    vsock_rx_bytes = vm.vsock_rx_bytes,
+                    vsock_tx_bytes = vm.vsock_tx_bytes,
+                    "Firecracker metrics for VM"
+                );
+                crate::metrics::get().record_vm(&vm);
+            }
+            Err(e) => tracing::warn!("Firecracker metrics reader failed: {e}"),
+        }
+    }
 }
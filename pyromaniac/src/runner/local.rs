@@ -0,0 +1,152 @@
+use super::backend::{BootError, Connection, Sandbox, SandboxBackend};
+use super::pyrod_client;
+use crate::config::{Config, LanguageConfig};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Stdio;
+use tempfile::TempDir;
+use tokio::net::UnixListener;
+use tokio::process::{Child, Command};
+
+/// Runs inside the new namespaces to build the sandbox's root filesystem and start pyrod
+/// Takes the image tarball, the directory to mount the new root on, the directory holding
+/// the socket to connect back on, and the tmpfs size in MiB
+const SETUP_SCRIPT: &str = r#"
+set -e
+image="$1"; root="$2"; sock="$3"; size="$4"
+
+mount -t tmpfs -o "size=${size}m,mode=755" tmpfs "$root"
+tar -x -C "$root" -f "$image" --exclude='dev/*'
+
+mkdir -p "$root/dev" "$root/proc" "$root/run/pyromaniac" "$root/.old"
+# pyrod isn't init here, so we have to make /tmp for it
+mkdir -p -m 1777 "$root/tmp"
+
+# device nodes can't be created in a user namespace, so borrow the host's
+for d in null zero random urandom; do
+    touch "$root/dev/$d"
+    mount --bind "/dev/$d" "$root/dev/$d"
+done
+
+mount --bind "$sock" "$root/run/pyromaniac"
+# proc has to be mounted while the host's is still visible
+mount -t proc -o nosuid,noexec,nodev proc "$root/proc"
+
+cd "$root"
+pivot_root . .old
+umount -l /.old
+rmdir /.old
+
+exec /bin/pyrod --no-init --transport unix:/run/pyromaniac/pyrod.sock_5000
+"#;
+
+/// Runs pyrod as a host process inside its own user, mount, pid and network namespaces
+/// This is nowhere near as strong as a VM, and is only meant for development and CI where there's no KVM
+/// Needs `newuidmap` and a subuid range for the user running the server, so that
+/// pyrod can run code as the untrusted user.
+pub struct LocalBackend;
+
+/// Holds the namespaced pyrod process and its temp directory
+/// Killing the process takes down its pid namespace, which takes the tmpfs root with it
+pub struct LocalSandbox {
+    process: Child,
+    /// Bound before pyrod starts, and handed over once it connects
+    listener: Option<UnixListener>,
+    _dir: TempDir,
+}
+
+impl LocalSandbox {
+    #[tracing::instrument]
+    async fn spawn(image: &Path, tmpfs_size_mib: u32, logs: &Path) -> Result<Self> {
+        let tempdir = TempDir::new().context("Failed to create tempdir")?;
+        let root = tempdir.path().join("root");
+        let sock_dir = tempdir.path().join("sock");
+
+        std::fs::create_dir(&root).context("Failed to create root mount point")?;
+        std::fs::create_dir(&sock_dir).context("Failed to create socket dir")?;
+
+        //root in the user namespace is not us, so it needs to be able to get in here
+        std::fs::set_permissions(tempdir.path(), std::fs::Permissions::from_mode(0o755))
+            .context("Could not set perms for tempdir")?;
+        std::fs::set_permissions(&sock_dir, std::fs::Permissions::from_mode(0o777))
+            .context("Could not set perms for socket dir")?;
+        let listener = pyrod_client::listen(sock_dir.join("pyrod.sock_5000"))?;
+
+        let mut command = Command::new("unshare");
+        command
+            .args(["--map-auto", "--setuid", "0", "--setgid", "0"])
+            .args(["--mount", "--propagation", "private"])
+            .args(["--pid", "--fork", "--kill-child"])
+            .arg("--net")
+            .args(["sh", "-c", SETUP_SCRIPT, "sh"])
+            .arg(image)
+            .arg(&root)
+            .arg(&sock_dir)
+            .arg(tmpfs_size_mib.to_string());
+        let (stdout, stderr) = crate::logs::console(&logs.join("console.log"))?;
+
+        let process = command
+            .kill_on_drop(true) //IMPORTANT - for process to be killed
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr)
+            .spawn()
+            .context("Failed to spawn unshare")?;
+
+        tracing::info!("Local sandbox at path {:?} started", tempdir.path());
+
+        Ok(LocalSandbox {
+            process,
+            listener: Some(listener),
+            _dir: tempdir,
+        })
+    }
+}
+
+#[async_trait]
+impl SandboxBackend for LocalBackend {
+    async fn spawn(
+        &self,
+        config: &Config,
+        lang: &LanguageConfig,
+        logs: &Path,
+    ) -> Result<Box<dyn Sandbox>, BootError> {
+        let image = &lang.rootfs;
+
+        let sandbox = LocalSandbox::spawn(image, config.runner_config.local.tmpfs_size_mib, logs)
+            .await
+            .map_err(BootError::Spawn)?;
+
+        Ok(Box::new(sandbox))
+    }
+}
+
+#[async_trait]
+impl Sandbox for LocalSandbox {
+    async fn connect(&mut self) -> Result<Connection, BootError> {
+        let listener = self
+            .listener
+            .take()
+            .ok_or_else(|| BootError::Connect(anyhow::anyhow!("Already connected")))?;
+
+        tokio::select! {
+            client = pyrod_client::accept(&listener) => match client {
+                Ok(client) => Ok((client, listener)),
+                Err(e) => Err(BootError::Connect(e)),
+            },
+            status = self.process.wait() => match status {
+                Ok(status) => Err(BootError::EarlyExit(Some(status))),
+                Err(e) => Err(BootError::Connect(e.into())),
+            },
+        }
+    }
+
+    async fn teardown(mut self: Box<Self>) {
+        //--kill-child means pyrod goes down with unshare
+        if let Err(e) = self.process.kill().await {
+            tracing::warn!("Failed to kill sandbox process: {e}");
+        }
+    }
+}
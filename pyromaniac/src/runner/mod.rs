@@ -1,33 +1,229 @@
+mod backend;
 mod firecracker;
+mod local;
 mod pyrod_client;
 
-use anyhow::Result;
-pub use firecracker::*;
-use pyrod_service::Language;
-use std::path::PathBuf;
+use crate::cache;
+use crate::config::{Backend, Config, LanguageConfig};
+use crate::logs::RunLogs;
+use anyhow::{anyhow, Result};
+use backend::{BootError, Sandbox, SandboxBackend};
+use pyrod_service::{Artifact, Language, Limits, PyrodClient, Submission};
+use std::path::Path;
+use std::time::Instant;
+use tokio::net::UnixListener;
+use tracing::Instrument;
 
-fn get_rootfs(lang: Language) -> PathBuf {
-    crate::config::get()
-        .resource_path
-        .join(format!("rootfs-{lang}.ext4"))
+fn get_backend(backend: Backend) -> &'static dyn SandboxBackend {
+    //leaked for the same reason as runners in pyrod, they're zero-sized anyway
+    match backend {
+        Backend::Firecracker => Box::leak(Box::new(firecracker::FirecrackerBackend)),
+        Backend::Local => Box::leak(Box::new(local::LocalBackend)),
+    }
 }
 
-#[tracing::instrument(skip(code, input))]
-pub async fn run_code(lang: Language, code: String, input: String) -> Result<(String, String)> {
-    let config = firecracker::VmConfig {
-        runner: &crate::config::get().runner_config,
-        rootfs: get_rootfs(lang),
-        kernel: crate::config::get().resource_path.join("kernel.bin"),
+/// A booted sandbox with pyrod connected and ready to take RPC calls
+struct Vm {
+    client: PyrodClient,
+    _listener: UnixListener,
+    sandbox: Box<dyn Sandbox>,
+}
+
+impl Vm {
+    /// Tear the sandbox down in the background, so nothing waits for it to shut down
+    /// It stays in the current span, so its logs and metrics end up with the run's
+    fn teardown(self) {
+        //hang up on pyrod before tearing down, so it can shut down cleanly
+        let Vm { sandbox, .. } = self;
+        tokio::spawn(sandbox.teardown().instrument(tracing::Span::current()));
+    }
+}
+
+/// Spawn a sandbox and wait for pyrod to connect back to us, bounded by the boot timeout
+async fn boot(config: &Config, lang: &LanguageConfig, logs: &Path) -> Result<Vm, BootError> {
+    let timeout = config.runner_config.boot_timeout;
+
+    let mut sandbox = get_backend(config.runner_config.backend)
+        .spawn(config, lang, logs)
+        .await?;
+
+    let (client, listener) = tokio::time::timeout(timeout, sandbox.connect())
+        .await
+        .map_err(|_| BootError::NoConnect(timeout))??;
+
+    Ok(Vm {
+        client,
+        _listener: listener,
+        sandbox,
+    })
+}
+
+/// Boot a VM, retrying up to the configured number of times if it fails to come up
+/// Each attempt gets its own log directory, so we can see why the failed ones failed
+/// Failures that would only happen again, like a missing rootfs, aren't retried
+async fn boot_with_retry(config: &Config, lang: &LanguageConfig, logs: &RunLogs) -> Result<Vm> {
+    let attempts = config.runner_config.boot_retries + 1;
+
+    let mut last_err = None;
+    let mut tried = 0;
+    for attempt in 1..=attempts {
+        tried = attempt;
+        let start = Instant::now();
+        let span = tracing::info_span!("Boot attempt", attempt, attempts);
+
+        let booted = match logs.attempt(attempt) {
+            Ok(dir) => boot(config, lang, &dir).instrument(span.clone()).await,
+            Err(e) => Err(BootError::Spawn(e)),
+        };
+
+        match booted {
+            Ok(vm) => {
+                let elapsed = start.elapsed();
+                span.in_scope(|| tracing::info!("VM booted in {:?}", elapsed));
+                crate::metrics::get().record_boot(elapsed);
+                return Ok(vm);
+            }
+            Err(e) => {
+                crate::metrics::get().record_boot_failure();
+                span.in_scope(|| {
+                    tracing::warn!("VM failed to boot after {:?}: {e}", start.elapsed())
+                });
+                let retry = e.is_transient();
+                last_err = Some(e);
+                if !retry {
+                    break;
+                }
+            }
+        }
+    }
+
+    Err(anyhow!(
+        "VM failed to boot after {tried} attempt{}: {}",
+        if tried == 1 { "" } else { "s" },
+        last_err.expect("At least one boot attempt is always made")
+    ))
+}
+
+/// What a run printed
+#[derive(Debug)]
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+    /// What the compiler printed, eg warnings, or the errors if it failed
+    pub compile_output: String,
+    /// Compiling failed, so nothing was run
+    pub compile_error: bool,
+    /// Structured results, for languages that write them out
+    pub results: Option<serde_json::Value>,
+    /// What compiling made, if it was asked for
+    pub artifact: Option<Artifact>,
+}
+
+/// Run some code in a fresh sandbox, under the settings in `config` for the whole run
+/// `spec` is the language's, with any options the request chose filled in
+/// Code that's been compiled before isn't compiled again, if the language can be cached
+#[tracing::instrument(
+    skip(config, lang, spec, submission, logs),
+    fields(lang = %lang.spec, version = ?lang.version, run_id = %logs.id)
+)]
+pub async fn run_code(
+    config: &Config,
+    lang: &LanguageConfig,
+    spec: Language,
+    mut submission: Submission,
+    logs: &RunLogs,
+) -> Result<Output> {
+    let key = cache::enabled(&spec).then(|| cache::key(lang, &spec, &submission));
+    let cached = match &key {
+        Some(key) => cache::get(key).await,
+        None => None,
     };
+    if key.is_some() {
+        tracing::info!("Cache {}", if cached.is_some() { "hit" } else { "miss" });
+        crate::metrics::get().record_cache(cached.is_some());
+    }
+    //if it's worth keeping, have pyrod send back what compiling made
+    submission.return_artifact = key.is_some() && cached.is_none();
 
     tracing::debug!("Booting new VM...");
 
-    let machine = firecracker::Machine::spawn(config).await?;
+    let vm = boot_with_retry(config, lang, logs).await?;
 
-    tracing::debug!("VM process spawned, chroot at {:?}", machine.chroot);
+    let output = match cached {
+        Some(entry) => pyrod_client::run_precompiled(
+            &vm.client,
+            spec,
+            entry.artifact,
+            submission,
+            lang.limits(),
+        )
+        .await
+        .map(|output| Output {
+            compile_output: entry.compile_output,
+            ..output
+        }),
+        None => pyrod_client::run_code(&vm.client, spec, submission, lang.limits()).await,
+    };
 
-    let output =
-        pyrod_client::run_code(machine.chroot.join("pyrod.sock_5000"), lang, code, input).await?;
+    vm.teardown();
 
+    let mut output = output?;
+    if let (Some(key), Some(artifact)) = (key, output.artifact.take()) {
+        let entry = cache::Entry {
+            artifact,
+            compile_output: output.compile_output.clone(),
+        };
+        if let Err(e) = cache::put(key, entry).await {
+            tracing::warn!("Failed to cache compiled code: {e:#}");
+        }
+    }
     Ok(output)
 }
+
+/// A sandbox running a language's REPL, which is kept until it's closed
+pub struct SessionVm {
+    vm: Vm,
+    limits: Limits,
+}
+
+impl SessionVm {
+    /// Boot a sandbox and start the REPL in it
+    /// `spec` is the language's with any options filled in, and has to have a REPL
+    #[tracing::instrument(
+        skip(config, lang, spec, logs),
+        fields(lang = %lang.spec, version = ?lang.version, session_id = %logs.id)
+    )]
+    pub async fn open(
+        config: &Config,
+        lang: &LanguageConfig,
+        spec: Language,
+        logs: &RunLogs,
+    ) -> Result<SessionVm> {
+        let vm = boot_with_retry(config, lang, logs).await?;
+        let limits = lang.limits();
+        if let Err(e) = pyrod_client::open_session(&vm.client, spec, limits).await {
+            vm.teardown();
+            return Err(e);
+        }
+        Ok(SessionVm { vm, limits })
+    }
+
+    /// Run a snippet in the REPL
+    /// If pyrod says the session timed out or ended, the REPL is gone and the session should be closed
+    pub async fn eval(&mut self, code: String) -> Result<Output> {
+        pyrod_client::eval(&self.vm.client, code, self.limits).await
+    }
+
+    pub async fn close(self) {
+        pyrod_client::close_session(&self.vm.client).await;
+        self.vm.teardown();
+    }
+}
+
+impl std::fmt::Debug for SessionVm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionVm")
+            .field("limits", &self.limits)
+            .finish_non_exhaustive()
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{anyhow, Context, Result};
-use pyrod_service::PyrodClient;
+use pyrod_service::{Artifact, PyrodClient, RunOutput};
 use std::os::unix::fs::PermissionsExt;
 use std::{ffi::OsString, fmt::Debug};
 use std::{path::Path, time::SystemTime};
@@ -8,8 +8,13 @@ use tarpc::tokio_serde::formats::Bincode;
 use tarpc::tokio_util::codec::length_delimited::LengthDelimitedCodec;
 use tokio::net::UnixListener;
 
+use super::Output;
+
+/// Listen on the VM's vsock socket, which has to happen before the sandbox starts
+/// so pyrod can't try to connect before there's anything there
+/// The listener has to be kept alive for as long as the client is in use
 #[tracing::instrument]
-async fn connect(sock: impl AsRef<Path> + Debug) -> Result<(PyrodClient, UnixListener)> {
+pub fn listen(sock: impl AsRef<Path> + Debug) -> Result<UnixListener> {
     let sock = sock.as_ref();
 
     //we can't just use tarpc::unix::connect because we need to establish the connection with the port number over the raw stream first
@@ -29,58 +34,144 @@ async fn connect(sock: impl AsRef<Path> + Debug) -> Result<(PyrodClient, UnixLis
         "Started listening for pyrod process on unix socket {:?}",
         sock
     );
+    Ok(listener)
+}
 
+/// Wait for pyrod to connect back to us on a socket from `listen`
+#[tracing::instrument(skip(listener))]
+pub async fn accept(listener: &UnixListener) -> Result<PyrodClient> {
     let (stream, addr) = listener.accept().await?;
-    tracing::info!(
-        "Accepted connection on socket {:?} with addr {:?}",
-        sock,
-        addr
-    );
+    tracing::info!("Accepted connection from pyrod with addr {:?}", addr);
 
     //we can hand over the stream to tarpc now
     //build transport layer using serde bincode and length-delimited frames
     let transport = tarpc::serde_transport::new(
-        LengthDelimitedCodec::builder().new_framed(stream),
+        LengthDelimitedCodec::builder()
+            .max_frame_length(pyrod_service::MAX_FRAME_LENGTH)
+            .new_framed(stream),
         Bincode::default(),
     );
 
     //we create a tarpc *client* on this end, because we're the one making RPC calls
     let client = PyrodClient::new(Default::default(), transport).spawn();
-    tracing::debug!("Connection to pyrod established on socket {:?}", sock);
-    Ok((client, listener))
+    tracing::debug!("Connection to pyrod established");
+    Ok(client)
 }
 
-#[tracing::instrument(skip(code, input, lang))]
+#[tracing::instrument(skip(client, submission, lang))]
 #[must_use]
 pub async fn run_code(
-    sock: impl AsRef<Path> + Debug,
+    client: &PyrodClient,
     lang: pyrod_service::Language,
-    code: String,
-    input: String,
-) -> Result<(String, String)> {
-    let (client, _l) = connect(sock).await.context("Failed to create RPC client")?;
-
+    submission: pyrod_service::Submission,
+    limits: pyrod_service::Limits,
+) -> Result<Output> {
     // ping commented out for speed
     // client.ping(context::current()).await?;
     // tracing::info!("Got Pong from VM");
 
     let mut ctx = context::current();
-    let timeouts = (
-        crate::config::get().runner_config.compile_timeout,
-        crate::config::get().runner_config.run_timeout,
-    );
 
     //include 5 seconds of slack
-    ctx.deadline = SystemTime::now() + timeouts.0 + timeouts.1 + std::time::Duration::from_secs(5);
-    let (stdout, stderr) = client
-        .run_code(ctx, lang, code, input, timeouts)
+    ctx.deadline = SystemTime::now()
+        + limits.compile_timeout
+        + limits.run_timeout
+        + std::time::Duration::from_secs(5);
+    let name = lang.name.clone();
+    let output = client
+        .run_code(ctx, lang, submission, limits)
+        .await?
+        .map_err(anyhow::Error::from)?;
+    record(&name, &output);
+    convert(output)
+}
+
+/// Run code with what compiling it made last time, instead of compiling it
+#[tracing::instrument(skip(client, artifact, submission, lang))]
+#[must_use]
+pub async fn run_precompiled(
+    client: &PyrodClient,
+    lang: pyrod_service::Language,
+    artifact: Artifact,
+    submission: pyrod_service::Submission,
+    limits: pyrod_service::Limits,
+) -> Result<Output> {
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + limits.run_timeout + std::time::Duration::from_secs(5);
+    let name = lang.name.clone();
+    let output = client
+        .run_precompiled(ctx, lang, artifact, submission, limits)
         .await?
         .map_err(anyhow::Error::from)?;
+    record(&name, &output);
+    convert(output)
+}
 
+/// Start the language's REPL in the VM, for `eval` to run snippets in
+#[tracing::instrument(skip(client, lang))]
+pub async fn open_session(
+    client: &PyrodClient,
+    lang: pyrod_service::Language,
+    limits: pyrod_service::Limits,
+) -> Result<()> {
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + limits.run_timeout + std::time::Duration::from_secs(5);
+    client
+        .open_session(ctx, lang, limits)
+        .await?
+        .map_err(anyhow::Error::from)
+}
+
+/// Run a snippet in the VM's session. pyrod's errors can be downcast to `RunError`
+#[tracing::instrument(skip(client, code))]
+pub async fn eval(
+    client: &PyrodClient,
+    code: String,
+    limits: pyrod_service::Limits,
+) -> Result<Output> {
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + limits.run_timeout + std::time::Duration::from_secs(5);
+    let output = client.eval(ctx, code).await?.map_err(anyhow::Error::from)?;
+    tracing::info!("Ran in {:?}", output.run_time);
+    convert(output)
+}
+
+/// Kill the session's REPL. Errors don't matter, the VM's about to be torn down anyway
+pub async fn close_session(client: &PyrodClient) {
+    if let Err(e) = client.close_session(context::current()).await {
+        tracing::debug!("Failed to close session: {e}");
+    }
+}
+
+/// Log and keep metrics on how long it took
+fn record(name: &str, output: &RunOutput) {
+    tracing::info!(
+        "Compiled in {:?}, ran in {:?}",
+        output.compile_time,
+        output.run_time
+    );
+    crate::metrics::get().record_run(name, output.compile_time, output.run_time);
+}
+
+/// Check everything that should be is valid
+fn convert(output: RunOutput) -> Result<Output> {
     let convert = |s: OsString| {
         s.into_string()
             .map_err(|_| anyhow!("Output was not valid UTF8, could not convet to string"))
     };
 
-    Ok((convert(stdout)?, convert(stderr)?))
+    let results = output
+        .results
+        .map(|r| serde_json::from_str(&r))
+        .transpose()
+        .context("Results were not valid JSON")?;
+
+    Ok(Output {
+        stdout: convert(output.stdout)?,
+        stderr: convert(output.stderr)?,
+        compile_output: convert(output.compile_output)?,
+        compile_error: output.compile_error,
+        results,
+        artifact: output.artifact,
+    })
 }
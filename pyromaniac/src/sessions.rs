@@ -0,0 +1,210 @@
+//! REPL sessions, each with a VM that's kept running between requests rather than torn down after one run.
+//! Every session holds one of the VM permits until it's closed, either by a request or by the reaper once
+//! nothing's been run in it for the idle timeout.
+
+use crate::config::{Config, LanguageConfig};
+use crate::logs::RunLogs;
+use crate::runner::{Output, SessionVm};
+use pyrod_service::{Language, RunError};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("No session {0}")]
+    NotFound(Uuid),
+    /// The REPL exited or a snippet timed out, so the session was closed
+    #[error("Session {0} has ended: {1}")]
+    Ended(Uuid, RunError),
+    #[error("There are already {0} sessions open")]
+    TooMany(usize),
+    #[error("There are no VMs free for a session")]
+    NoVms,
+    #[error("{0:#}")]
+    Other(anyhow::Error),
+}
+
+#[derive(Debug)]
+struct Session {
+    /// None once it's been closed, for anything still waiting to use it
+    vm: tokio::sync::Mutex<Option<SessionVm>>,
+    last_used: Mutex<Instant>,
+    logs: RunLogs,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Session {
+    async fn close(&self) {
+        if let Some(vm) = self.vm.lock().await.take() {
+            vm.close().await;
+            self.logs.finish();
+            tracing::info!("Closed session {}", self.logs.id);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Sessions {
+    open: BTreeMap<Uuid, Arc<Session>>,
+    /// Ones still booting, which count towards the limit too
+    opening: usize,
+}
+
+static SESSIONS: Mutex<Sessions> = Mutex::new(Sessions {
+    open: BTreeMap::new(),
+    opening: 0,
+});
+
+/// Boot a VM for a new session, returning its id
+/// Fails straight away if there's no VM free, rather than queueing like a run would
+pub async fn open(
+    config: &Config,
+    lang: &LanguageConfig,
+    spec: Language,
+    vms: Arc<Semaphore>,
+) -> Result<Uuid, SessionError> {
+    let permit = vms.try_acquire_owned().map_err(|_| SessionError::NoVms)?;
+    let max = config.sessions.max_sessions;
+    let opening = Opening::start(max)?;
+    let (vm, logs) = boot(config, lang, spec).await?;
+
+    let id = logs.id;
+    let session = Session {
+        vm: tokio::sync::Mutex::new(Some(vm)),
+        last_used: Mutex::new(Instant::now()),
+        logs,
+        _permit: permit,
+    };
+    opening.finish(id, session);
+    tracing::info!("Opened session {id}");
+    Ok(id)
+}
+
+/// A slot taken for a session that's booting, given back when it's done or the request's dropped
+struct Opening;
+
+impl Opening {
+    fn start(max: usize) -> Result<Opening, SessionError> {
+        let mut sessions = SESSIONS.lock().unwrap();
+        if sessions.open.len() + sessions.opening >= max {
+            return Err(SessionError::TooMany(max));
+        }
+        sessions.opening += 1;
+        Ok(Opening)
+    }
+
+    /// Swap the slot for the session, so there's never a moment it isn't counted
+    fn finish(self, id: Uuid, session: Session) {
+        let mut sessions = SESSIONS.lock().unwrap();
+        sessions.opening -= 1;
+        sessions.open.insert(id, Arc::new(session));
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Opening {
+    fn drop(&mut self) {
+        SESSIONS.lock().unwrap().opening -= 1;
+    }
+}
+
+/// Boot the VM and start the REPL in it, bounded the same as a run
+async fn boot(
+    config: &Config,
+    lang: &LanguageConfig,
+    spec: Language,
+) -> Result<(SessionVm, RunLogs), SessionError> {
+    //the logs are kept under the session's id, the same as a run's
+    let logs = RunLogs::new().await.map_err(SessionError::Other)?;
+    let deadline = config.runner_config.boot_timeout * (config.runner_config.boot_retries + 1)
+        + lang.run_timeout
+        + Duration::from_secs(10);
+    let vm = tokio::time::timeout(deadline, SessionVm::open(config, lang, spec, &logs))
+        .await
+        .map_err(|e| SessionError::Other(e.into()))
+        .and_then(|vm| vm.map_err(SessionError::Other));
+    match vm {
+        Ok(vm) => Ok((vm, logs)),
+        Err(e) => {
+            logs.finish();
+            Err(e)
+        }
+    }
+}
+
+/// Run a snippet in a session, one at a time if there's more than one request for it
+pub async fn eval(id: Uuid, code: String) -> Result<Output, SessionError> {
+    let session = get(id)?;
+    let output = {
+        let mut vm = session.vm.lock().await;
+        //closed while we were waiting for it
+        let vm = vm.as_mut().ok_or(SessionError::NotFound(id))?;
+        let output = vm.eval(code).await;
+        *session.last_used.lock().unwrap() = Instant::now();
+        output
+    };
+
+    let e = match output {
+        Ok(output) => return Ok(output),
+        Err(e) => e,
+    };
+    match e.downcast::<RunError>() {
+        //pyrod's already killed the REPL, so there's no point keeping the VM
+        Ok(e @ (RunError::RunTimeout(_) | RunError::SessionEnded | RunError::NoSession)) => {
+            let _ = close(id).await;
+            Err(SessionError::Ended(id, e))
+        }
+        Ok(e) => Err(SessionError::Other(e.into())),
+        Err(e) => Err(SessionError::Other(e)),
+    }
+}
+
+/// Close a session and tear down its VM, waiting for anything running in it first
+pub async fn close(id: Uuid) -> Result<(), SessionError> {
+    let session = SESSIONS
+        .lock()
+        .unwrap()
+        .open
+        .remove(&id)
+        .ok_or(SessionError::NotFound(id))?;
+    session.close().await;
+    Ok(())
+}
+
+fn get(id: Uuid) -> Result<Arc<Session>, SessionError> {
+    SESSIONS
+        .lock()
+        .unwrap()
+        .open
+        .get(&id)
+        .cloned()
+        .ok_or(SessionError::NotFound(id))
+}
+
+/// Close sessions nothing's been run in for the idle timeout, every few seconds forever
+pub async fn reap() {
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+        let timeout = crate::config::get().sessions.idle_timeout;
+        let idle: Vec<Uuid> = SESSIONS
+            .lock()
+            .unwrap()
+            .open
+            .iter()
+            .filter(|(_, session)| {
+                //one that's running something isn't idle, however long it's been
+                session.vm.try_lock().is_ok()
+                    && session.last_used.lock().unwrap().elapsed() >= timeout
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in idle {
+            tracing::info!("Session {id} has been idle for {timeout:?}, closing it");
+            let _ = close(id).await;
+        }
+    }
+}
@@ -0,0 +1,582 @@
+//! Drives pyrod's RPC service over a unix socket, with no VM involved
+//! Running code needs root (pyrod drops to the untrusted uid) and writes to /tmp on the host,
+//! so those tests are skipped when not running as root.
+
+use pyrod_service::{Language, Limits, PyrodClient, Repl, RunError, RunOutput, Submission};
+use std::time::{Duration, SystemTime};
+use tarpc::context;
+use tarpc::tokio_serde::formats::Bincode;
+use tarpc::tokio_util::codec::length_delimited::LengthDelimitedCodec;
+use tempfile::TempDir;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+
+/// Runners all share the same files in /tmp, so only one can run at a time
+static RUN_LOCK: Mutex<()> = Mutex::const_new(());
+
+const LIMITS: Limits = Limits {
+    compile_timeout: Duration::from_secs(10),
+    run_timeout: Duration::from_secs(10),
+    max_output: 1024 * 1024,
+};
+
+/// Start pyrod in-process and connect to it the same way pyromaniac does
+/// pyrod connects to us, so we listen and it dials in
+async fn connect() -> (PyrodClient, TempDir) {
+    let dir = TempDir::new().unwrap();
+    let sock = dir.path().join("pyrod.sock");
+    let listener = UnixListener::bind(&sock).unwrap();
+
+    tokio::spawn(async move {
+        let stream = UnixStream::connect(sock).await.unwrap();
+        pyrod_service::serve(stream).await;
+    });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let transport = tarpc::serde_transport::new(
+        LengthDelimitedCodec::builder()
+            .max_frame_length(pyrod_service::MAX_FRAME_LENGTH)
+            .new_framed(stream),
+        Bincode::default(),
+    );
+
+    (PyrodClient::new(Default::default(), transport).spawn(), dir)
+}
+
+/// The same as the built-in shell definitions
+fn shell(name: &str, program: &str) -> Language {
+    Language {
+        name: name.to_owned(),
+        workdir: "/tmp".into(),
+        source: "code.sh".into(),
+        compile: None,
+        run: vec![program.to_owned(), "{source}".to_owned()],
+        env: Default::default(),
+        vars: Default::default(),
+        results: None,
+        manifest: None,
+        install: None,
+        artifacts: vec![],
+        repl: None,
+    }
+}
+
+async fn run(
+    lang: Language,
+    code: &str,
+    input: &str,
+    limits: Limits,
+) -> Result<RunOutput, RunError> {
+    let submission = Submission {
+        code: code.to_owned(),
+        input: input.to_owned(),
+        ..Default::default()
+    };
+    run_submission(lang, submission, limits).await
+}
+
+async fn run_submission(
+    lang: Language,
+    submission: Submission,
+    limits: Limits,
+) -> Result<RunOutput, RunError> {
+    let (client, _dir) = connect().await;
+    let mut ctx = context::current();
+    ctx.deadline =
+        SystemTime::now() + limits.compile_timeout + limits.run_timeout + Duration::from_secs(5);
+    client
+        .run_code(ctx, lang, submission, limits)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn ping() {
+    let (client, _dir) = connect().await;
+    let pong = client.ping(context::current()).await.unwrap();
+    assert_eq!(pong, "Pong!");
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn sh_echoes_input() {
+    let _lock = RUN_LOCK.lock().await;
+
+    let RunOutput { stdout, stderr, .. } = run(
+        shell("sh", "/bin/sh"),
+        "read name; echo \"Hello, $name!\"; echo oops >&2",
+        "pyro\n",
+        LIMITS,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(stdout, "Hello, pyro!\n");
+    assert_eq!(stderr, "oops\n");
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn bash_runs_as_untrusted_user() {
+    let _lock = RUN_LOCK.lock().await;
+
+    let RunOutput { stdout, .. } = run(shell("bash", "bash"), "echo $(id -u):$(id -g)", "", LIMITS)
+        .await
+        .unwrap();
+
+    assert_eq!(stdout, "111:111\n");
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn env_is_filled_in_like_commands() {
+    let _lock = RUN_LOCK.lock().await;
+
+    let lang = Language {
+        env: [("GREETING".to_owned(), "hello from {version}".to_owned())].into(),
+        vars: [("version".to_owned(), "1.2".to_owned())].into(),
+        ..shell("sh", "/bin/sh")
+    };
+    let RunOutput { stdout, .. } = run(lang, "echo $GREETING", "", LIMITS).await.unwrap();
+
+    assert_eq!(stdout, "hello from 1.2\n");
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn run_timeout() {
+    let _lock = RUN_LOCK.lock().await;
+
+    let limits = Limits {
+        run_timeout: Duration::from_millis(200),
+        ..LIMITS
+    };
+    let err = run(shell("sh", "/bin/sh"), "sleep 1", "", limits)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(err, RunError::RunTimeout(t) if t == limits.run_timeout));
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn output_is_capped() {
+    let _lock = RUN_LOCK.lock().await;
+
+    let limits = Limits {
+        max_output: 10,
+        ..LIMITS
+    };
+    let RunOutput { stdout, stderr, .. } = run(
+        shell("sh", "/bin/sh"),
+        "seq 1000; echo short >&2",
+        "",
+        limits,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(stdout, "1\n2\n3\n4\n5\n\n[3883 bytes truncated]");
+    assert_eq!(stderr, "short\n");
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn output_is_capped_between_characters() {
+    let _lock = RUN_LOCK.lock().await;
+
+    let limits = Limits {
+        max_output: 5,
+        ..LIMITS
+    };
+    //two bytes each, so the cap lands in the middle of the third
+    let output = run(shell("sh", "/bin/sh"), "printf 'ééééé'", "", limits)
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "éé\n[6 bytes truncated]");
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn large_input_and_output_do_not_deadlock() {
+    let _lock = RUN_LOCK.lock().await;
+
+    //more than a pipe buffer each way, so cat blocks writing before we've finished feeding it
+    let input = "x".repeat(1024 * 1024);
+    let RunOutput { stdout, .. } = run(shell("sh", "/bin/sh"), "cat", &input, LIMITS)
+        .await
+        .unwrap();
+
+    assert_eq!(stdout.len(), input.len());
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn background_processes_are_killed() {
+    let _lock = RUN_LOCK.lock().await;
+
+    //the backgrounded sleep holds stdout open, which would hang the run if it was left alive
+    let start = std::time::Instant::now();
+    let RunOutput { stdout, .. } = run(shell("sh", "/bin/sh"), "sleep 30 & echo done", "", LIMITS)
+        .await
+        .unwrap();
+
+    assert_eq!(stdout, "done\n");
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+#[ignore = "needs root and setsid"]
+async fn escaped_processes_do_not_hang_the_run() {
+    let _lock = RUN_LOCK.lock().await;
+
+    //setsid takes it out of the process group, so it isn't killed and keeps stdout open
+    let start = std::time::Instant::now();
+    let RunOutput { stdout, .. } = run(
+        shell("sh", "/bin/sh"),
+        "setsid sleep 3 & echo done",
+        "",
+        LIMITS,
+    )
+    .await
+    .unwrap();
+
+    assert!(stdout.to_string_lossy().starts_with("done\n"));
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test]
+#[ignore = "needs root and gcc"]
+async fn c_warnings_are_compile_output() {
+    let _lock = RUN_LOCK.lock().await;
+
+    //the same as the built-in definition, with the optimisation flag left out
+    let c = Language {
+        name: "c".to_owned(),
+        workdir: "/tmp".into(),
+        source: "code.c".into(),
+        compile: Some(
+            [
+                "/usr/bin/gcc",
+                "-std=c17",
+                "{opt}",
+                "-Wall",
+                "-Wextra",
+                "-o",
+                "/tmp/a.out",
+                "{source}",
+                "-lm",
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+        ),
+        run: vec!["/tmp/a.out".to_owned()],
+        env: Default::default(),
+        vars: [("opt".to_owned(), "".to_owned())].into(),
+        results: None,
+        manifest: None,
+        install: None,
+        artifacts: vec![],
+        repl: None,
+    };
+    let code = "#include <stdio.h>\nint main(void) { int unused; puts(\"hi\"); return 0; }\n";
+    let output = run(c.clone(), code, "", LIMITS).await.unwrap();
+
+    assert_eq!(output.stdout, "hi\n");
+    assert!(output
+        .compile_output
+        .to_string_lossy()
+        .contains("unused variable"));
+    //both steps are timed
+    assert!(output.compile_time.is_some());
+    assert!(output.run_time.is_some());
+    assert!(!output.compile_error);
+
+    //errors go in the same place, and are marked so they can't be mistaken for the program's
+    let output = run(c, "int main(void) { return x; }\n", "", LIMITS)
+        .await
+        .unwrap();
+    assert!(output.compile_error);
+    assert!(output
+        .compile_output
+        .to_string_lossy()
+        .contains("undeclared"));
+    assert_eq!(output.stderr, "");
+    assert!(output.run_time.is_none());
+}
+
+#[tokio::test]
+#[ignore = "needs root and node"]
+async fn javascript_module_option_picks_extension() {
+    let _lock = RUN_LOCK.lock().await;
+
+    let node = |module: &str| Language {
+        name: "javascript".to_owned(),
+        workdir: "/tmp".into(),
+        source: "code.{module}js".into(),
+        compile: None,
+        run: vec!["/usr/bin/node".to_owned(), "{source}".to_owned()],
+        env: Default::default(),
+        vars: [("module".to_owned(), module.to_owned())].into(),
+        results: None,
+        manifest: None,
+        install: None,
+        artifacts: vec![],
+        repl: None,
+    };
+
+    let cjs = run(node("c"), "console.log(typeof require)", "", LIMITS)
+        .await
+        .unwrap();
+    assert_eq!(cjs.stdout, "function\n");
+
+    let esm = run(
+        node("m"),
+        "import { EOL } from 'node:os'; console.log(typeof require, EOL.length)",
+        "",
+        LIMITS,
+    )
+    .await
+    .unwrap();
+    assert_eq!(esm.stdout, "undefined 1\n");
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn results_file_is_returned() {
+    let _lock = RUN_LOCK.lock().await;
+
+    let lang = Language {
+        results: Some("/tmp/results.json".into()),
+        ..shell("sh", "/bin/sh")
+    };
+    let output = run(
+        lang.clone(),
+        "echo '[{\"n\": 1}]' > /tmp/results.json; echo hi",
+        "",
+        LIMITS,
+    )
+    .await
+    .unwrap();
+    assert_eq!(output.stdout, "hi\n");
+    assert_eq!(output.results.as_deref(), Some("[{\"n\": 1}]\n"));
+
+    //all or nothing, so it's never half a JSON document
+    let limits = Limits {
+        max_output: 4,
+        ..LIMITS
+    };
+    let output = run(
+        lang.clone(),
+        "echo '[1, 2, 3]' > /tmp/results.json",
+        "",
+        limits,
+    )
+    .await
+    .unwrap();
+    assert_eq!(output.results, None);
+    assert_eq!(output.stderr, "\n[results over 4 bytes dropped]");
+
+    //opening a FIFO would wait for a writer that's never coming
+    let output = run(lang, "mkfifo /tmp/results.json", "", LIMITS)
+        .await
+        .unwrap();
+    assert_eq!(output.results, None);
+    assert_eq!(output.stderr, "\n[results aren't a regular file, dropped]");
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn manifest_is_written_next_to_the_source() {
+    let _lock = RUN_LOCK.lock().await;
+
+    let submission = Submission {
+        code: "cat requirements.txt".to_owned(),
+        manifest: Some("numpy\n".to_owned()),
+        ..Default::default()
+    };
+    let lang = Language {
+        manifest: Some("requirements.txt".into()),
+        install: Some(vec!["/bin/echo".to_owned(), "installed".to_owned()]),
+        ..shell("sh", "/bin/sh")
+    };
+    let output = run_submission(lang.clone(), submission.clone(), LIMITS)
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "numpy\n");
+    assert_eq!(output.compile_output, "installed\n");
+    assert!(output.compile_time.is_some());
+
+    //there's nothing to install without one
+    let output = run(lang, "echo hi", "", LIMITS).await.unwrap();
+    assert_eq!(output.compile_output, "");
+    assert_eq!(output.compile_time, None);
+
+    //languages that don't take one say so
+    let err = run_submission(shell("sh", "/bin/sh"), submission, LIMITS)
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "sh doesn't take a manifest");
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn files_are_written_next_to_the_source() {
+    let _lock = RUN_LOCK.lock().await;
+
+    let submission = Submission {
+        code: ". lib/greeting.sh; echo $greeting".to_owned(),
+        files: [("lib/greeting.sh".into(), "greeting=hello\n".to_owned())].into(),
+        ..Default::default()
+    };
+    let output = run_submission(shell("sh", "/bin/sh"), submission, LIMITS)
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "hello\n");
+
+    let submission = Submission {
+        files: [("../etc/passwd".into(), String::new())].into(),
+        ..Default::default()
+    };
+    let err = run_submission(shell("sh", "/bin/sh"), submission, LIMITS)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, RunError::BadPath(_)));
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn artifacts_run_without_compiling() {
+    let _lock = RUN_LOCK.lock().await;
+
+    //"compiling" copies the source somewhere else, which is all running needs
+    let lang = Language {
+        compile: Some(
+            ["/bin/cp", "{source}", "/tmp/compiled.sh"]
+                .map(str::to_owned)
+                .to_vec(),
+        ),
+        run: ["/bin/sh", "/tmp/compiled.sh"].map(str::to_owned).to_vec(),
+        artifacts: vec!["/tmp/compiled.sh".into()],
+        ..shell("sh", "/bin/sh")
+    };
+    let submission = Submission {
+        code: "read name; echo \"Hello, $name!\"".to_owned(),
+        input: "pyro\n".to_owned(),
+        return_artifact: true,
+        ..Default::default()
+    };
+    let output = run_submission(lang.clone(), submission, LIMITS)
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "Hello, pyro!\n");
+    let artifact = output.artifact.unwrap();
+    assert_eq!(artifact.files.len(), 1);
+    assert_eq!(
+        artifact.files[0].path,
+        std::path::Path::new("/tmp/compiled.sh")
+    );
+
+    //so it has to be put back, and the code is never looked at
+    std::fs::remove_file("/tmp/compiled.sh").unwrap();
+    let (client, _dir) = connect().await;
+    let submission = Submission {
+        code: "echo not compiled".to_owned(),
+        input: "again\n".to_owned(),
+        ..Default::default()
+    };
+    let output = client
+        .run_precompiled(
+            context::current(),
+            lang.clone(),
+            artifact.clone(),
+            submission,
+            LIMITS,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(output.stdout, "Hello, again!\n");
+    assert_eq!(output.compile_time, None);
+
+    //artifacts only go where the language says they do
+    let mut elsewhere = artifact;
+    elsewhere.files[0].path = "/tmp/compiled.sh/../../etc/profile".into();
+    let err = client
+        .run_precompiled(
+            context::current(),
+            lang,
+            elsewhere,
+            Submission::default(),
+            LIMITS,
+        )
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert!(matches!(err, RunError::BadPath(_)));
+}
+
+#[tokio::test]
+#[ignore = "needs root"]
+async fn sessions_keep_state_between_snippets() {
+    let _lock = RUN_LOCK.lock().await;
+
+    //the same as the built-in bash definition
+    let lang = Language {
+        repl: Some(Repl {
+            command: vec!["/bin/bash".to_owned()],
+            eval: ". {source} < /dev/null\necho {marker}; echo {marker} >&2".to_owned(),
+        }),
+        ..shell("bash", "/bin/bash")
+    };
+    let limits = Limits {
+        run_timeout: Duration::from_secs(1),
+        ..LIMITS
+    };
+    let (client, _dir) = connect().await;
+    let eval = |code: &str| client.eval(context::current(), code.to_owned());
+
+    let err = eval("echo hi").await.unwrap().unwrap_err();
+    assert!(matches!(err, RunError::NoSession));
+
+    client
+        .open_session(context::current(), lang.clone(), limits)
+        .await
+        .unwrap()
+        .unwrap();
+    let output = eval("greeting=hello; cd /; printf partial")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(output.stdout, "partial");
+    let output = eval("echo $greeting from $PWD; ls /nope")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(output.stdout, "hello from /\n");
+    assert!(output.stderr.to_string_lossy().contains("No such file"));
+
+    //running out of time ends the session
+    let err = eval("sleep 5").await.unwrap().unwrap_err();
+    assert!(matches!(err, RunError::RunTimeout(_)));
+    let err = eval("echo hi").await.unwrap().unwrap_err();
+    assert!(matches!(err, RunError::NoSession));
+
+    //and so does the interpreter exiting
+    client
+        .open_session(context::current(), lang, limits)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(eval("echo $greeting").await.unwrap().unwrap().stdout, "\n");
+    let err = eval("exit 3").await.unwrap().unwrap_err();
+    assert!(matches!(err, RunError::SessionEnded));
+
+    //languages without a REPL can't have sessions
+    let err = client
+        .open_session(context::current(), shell("sh", "/bin/sh"), limits)
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(err.to_string(), "sh doesn't have a REPL");
+}
@@ -1,3 +1,4 @@
+# rootfs-size-mb: 50
 FROM bash:5.2
 
 # create service user for untrusted processes to run under
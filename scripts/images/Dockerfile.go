@@ -0,0 +1,34 @@
+# rootfs-size-mb: 800
+FROM golang:1.21-alpine3.18
+
+# the same as the run environment in languages.toml, so the build cache is hit
+ENV GOPATH=/go GOMODCACHE=/go/pkg/mod GOCACHE=/go/cache GOFLAGS=-mod=mod GOTOOLCHAIN=local CGO_ENABLED=0
+
+# create service user for untrusted processes to run under
+# system group, gid 111
+RUN addgroup -S -g 111 untrusted
+# system user, no password, no home dir, no shell, uid 111, group untrusted
+RUN adduser -S -D -H -s /bin/false -u 111 -G untrusted untrusted
+
+COPY target/x86_64-unknown-linux-musl/release/pyrod /bin
+
+# create go module ready for code to land in
+RUN mkdir /go_project
+WORKDIR /go_project
+RUN go mod init pyro
+
+# install useful modules
+RUN go get golang.org/x/exp@latest
+RUN go get github.com/samber/lo@latest
+RUN go get github.com/google/uuid@latest
+
+# build the standard library and the modules to warm the cache
+RUN printf 'package main\n\nimport (\n\t_ "github.com/google/uuid"\n\t_ "github.com/samber/lo"\n\t_ "golang.org/x/exp/constraints"\n\t_ "golang.org/x/exp/maps"\n\t_ "golang.org/x/exp/slices"\n)\n\nfunc main() {}\n' > main.go
+RUN go build std && go build -o /tmp/main . && rm /tmp/main
+
+# the module cache is read only, but the build cache and go.mod get written to
+RUN chown -R 111:111 /go_project /go/cache
+
+# copy this image's filesystem to the mounted filesystem when ran
+CMD for d in bin etc lib root sbin usr go go_project; do tar c "/$d" | tar x -C /rootfs; done && \
+    for d in dev proc run sys var; do mkdir /rootfs/${d}; done
@@ -1,3 +1,4 @@
+# rootfs-size-mb: 20
 FROM alpine:3.18
 
 # create service user for untrusted processes to run under
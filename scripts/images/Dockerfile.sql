@@ -0,0 +1,21 @@
+# rootfs-size-mb: 30
+# sqlite is built into pyrod, so all this needs is the fixture databases
+FROM alpine:3.18
+
+# create service user for untrusted processes to run under
+# system group, gid 111
+RUN addgroup -S -g 111 untrusted
+# system user, no password, no home dir, no shell, uid 111, group untrusted
+RUN adduser -S -D -H -s /bin/false -u 111 -G untrusted untrusted
+
+COPY target/x86_64-unknown-linux-musl/release/pyrod /bin
+
+# every scripts/images/fixtures/<name>.sql becomes a database that can be asked for by name
+COPY scripts/images/fixtures /fixtures
+RUN apk add --no-cache sqlite && \
+    for f in /fixtures/*.sql; do sqlite3 "${f%.sql}.db" < "$f"; done && \
+    rm /fixtures/*.sql && chmod 644 /fixtures/*.db && \
+    apk del sqlite
+
+CMD for d in bin etc fixtures lib root sbin usr; do tar c "/$d" | tar x -C /rootfs; done && \
+    for d in dev proc run sys var; do mkdir /rootfs/${d}; done
@@ -0,0 +1,39 @@
+-- a small shop, for the `shop` fixture in the sql image
+CREATE TABLE customers (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    city TEXT
+);
+
+CREATE TABLE products (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    price REAL NOT NULL
+);
+
+CREATE TABLE orders (
+    id INTEGER PRIMARY KEY,
+    customer_id INTEGER NOT NULL REFERENCES customers(id),
+    product_id INTEGER NOT NULL REFERENCES products(id),
+    quantity INTEGER NOT NULL,
+    ordered_at TEXT NOT NULL
+);
+
+INSERT INTO customers (id, name, city) VALUES
+    (1, 'Ada', 'London'),
+    (2, 'Grace', 'New York'),
+    (3, 'Linus', 'Helsinki'),
+    (4, 'Margaret', NULL);
+
+INSERT INTO products (id, name, price) VALUES
+    (1, 'Keyboard', 49.99),
+    (2, 'Mouse', 19.5),
+    (3, 'Monitor', 189.0),
+    (4, 'Cable', 4.25);
+
+INSERT INTO orders (id, customer_id, product_id, quantity, ordered_at) VALUES
+    (1, 1, 1, 1, '2023-01-04'),
+    (2, 1, 4, 3, '2023-01-04'),
+    (3, 2, 3, 2, '2023-02-11'),
+    (4, 3, 2, 1, '2023-02-20'),
+    (5, 2, 4, 10, '2023-03-02');
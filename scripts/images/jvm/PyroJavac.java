@@ -0,0 +1,10 @@
+import javax.tools.ToolProvider;
+
+/**
+ * javac, through the compiler API, so it can be started with our own JVM flags and class data sharing archive
+ */
+public class PyroJavac {
+    public static void main(String[] args) {
+        System.exit(ToolProvider.getSystemJavaCompiler().run(null, null, null, args));
+    }
+}
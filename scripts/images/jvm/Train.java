@@ -0,0 +1,43 @@
+import java.io.BufferedReader;
+import java.io.InputStreamReader;
+import java.util.*;
+import java.util.function.Function;
+import java.util.regex.Pattern;
+import java.util.stream.Collectors;
+import java.util.stream.IntStream;
+
+/**
+ * Does what programs usually do, so the classes it loads can go in the class data sharing archives
+ * It's compiled with PyroJavac, then run with some input
+ */
+public class Train {
+    record Point(int x, int y) {}
+
+    public static void main(String[] args) throws Exception {
+        BufferedReader reader = new BufferedReader(new InputStreamReader(System.in));
+        Scanner scanner = new Scanner(reader.readLine());
+        int a = scanner.nextInt(), b = scanner.nextInt();
+        String line = reader.readLine();
+
+        List<Integer> list = new ArrayList<>(List.of(a, b, a * b));
+        Collections.sort(list, Comparator.reverseOrder());
+        Map<String, Long> counts = Arrays.stream(line.split("\\s+"))
+                .collect(Collectors.groupingBy(Function.identity(), TreeMap::new, Collectors.counting()));
+        Set<Point> points = new HashSet<>();
+        Deque<Integer> queue = new ArrayDeque<>();
+        PriorityQueue<Long> heap = new PriorityQueue<>();
+        IntStream.range(0, 100).forEach(i -> {
+            points.add(new Point(i % 10, i / 10));
+            queue.add(i);
+            heap.add((long) i * i);
+        });
+
+        StringBuilder out = new StringBuilder();
+        out.append(String.format("%d %s %.2f%n", list.get(0), counts, Math.sqrt(a * b)));
+        out.append(Pattern.compile("o+").matcher(line).replaceAll("0")).append('\n');
+        out.append(points.size() + queue.size() + heap.poll()).append('\n');
+        out.append(Optional.ofNullable(System.getenv("HOME")).orElse("")).append('\n');
+        System.out.print(out);
+        System.out.println(new java.math.BigInteger("123456789").pow(3));
+    }
+}
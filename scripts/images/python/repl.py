@@ -0,0 +1,33 @@
+# the interpreter for python sessions, which is run with python -i so it keeps going afterwards
+# pyrod writes each snippet to a file, and sends a line calling __pyro_eval with its path
+# like the real REPL, a last expression's value is printed, and errors don't end the session
+import ast
+import os
+import sys
+import traceback
+
+sys.ps1 = sys.ps2 = ""
+# what pyrod sends comes in on stdin, so code can't have it
+sys.stdin = open(os.devnull)
+
+
+def __pyro_eval(path, marker):
+    namespace = sys.modules["__main__"].__dict__
+    try:
+        with open(path) as f:
+            tree = ast.parse(f.read(), "<snippet>")
+        last = None
+        if tree.body and isinstance(tree.body[-1], ast.Expr):
+            last = ast.Expression(tree.body.pop().value)
+        exec(compile(tree, "<snippet>", "exec"), namespace)
+        if last is not None:
+            sys.displayhook(eval(compile(last, "<snippet>", "eval"), namespace))
+    except SystemExit:
+        raise
+    except BaseException as e:
+        # without this function (or ast, for syntax errors) in the traceback
+        tb = None if isinstance(e, SyntaxError) else e.__traceback__.tb_next
+        traceback.print_exception(type(e), e, tb)
+    finally:
+        print(marker, flush=True)
+        print(marker, file=sys.stderr, flush=True)
@@ -8,44 +8,40 @@ if [ -z "$1" ]; then
     exit 0
 fi
 
-all=("rust" "python" "java" "bash" "sh")
+# every language with a dockerfile can be built
+all=($(ls scripts/images | sed -n 's/^Dockerfile\.//p'))
 
 if [ $lang == "all" ]; then
     echo "building rootfs for all languages"
     for l in "${all[@]}"; do
         echo "building rootfs for $l"
-        ./$0 $l
+        ./$0 $l $2
     done
     exit 0
 fi
-# 100MB rootfs - change if gonna run out of space
-
-case $lang in
-"rust")
-    size=1000
-    ;;
-"python")
-    size=100
-    ;;
-"java")
-    size=400
-    ;;
-"bash")
-    size=50
-    ;;
-"sh")
-    size=20
-    ;;
-*)
-    echo "unrecognised language $1"
+
+dockerfile="scripts/images/Dockerfile.$lang"
+if [ ! -f "$dockerfile" ]; then
+    echo "unrecognised language $1, no $dockerfile"
     exit 0
-    ;;
-esac
+fi
 
+# each dockerfile says how big its rootfs needs to be, 100MB if it doesn't
+size=$(sed -n 's/^# rootfs-size-mb: *//p' "$dockerfile")
+size=${size:-100}
 
 # build pyrod
 cargo build --release --bin=pyrod --target=x86_64-unknown-linux-musl
 
+# for the local backend we just want a tarball of the image, no ext4
+if [ "$2" == "--local" ]; then
+    sudo docker build --no-cache . -f "$dockerfile" -t "pyro-$lang"
+    container=$(sudo docker create "pyro-$lang")
+    sudo docker export "$container" > "resources/rootfs-$lang.tar"
+    sudo docker rm "$container"
+    exit 0
+fi
+
 dd if=/dev/zero of=rootfs.ext4 bs=1M count=$size
 /usr/sbin/mkfs.ext4 rootfs.ext4
 
@@ -53,7 +49,7 @@ dd if=/dev/zero of=rootfs.ext4 bs=1M count=$size
 sudo rm -rf /tmp/rootfs && mkdir /tmp/rootfs
 sudo mount rootfs.ext4 /tmp/rootfs
 
-sudo docker build --no-cache . -f "scripts/images/Dockerfile.$lang" -t "pyro-$lang"
+sudo docker build --no-cache . -f "$dockerfile" -t "pyro-$lang"
 sudo docker run -it --rm -v /tmp/rootfs:/rootfs "pyro-$lang"
 
 sudo umount /tmp/rootfs